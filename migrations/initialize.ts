@@ -172,7 +172,7 @@ async function main() {
       `   Total Reward Provided: ${stats.totalRewardProvided.toString()}`
     );
     console.log(
-      `   Last Update: ${new Date(stats.lastUpdateTime * 1000).toISOString()}`
+      `   Last Update: ${new Date(stats.lastUpdateTime.toNumber() * 1000).toISOString()}`
     );
 
     console.log("\n🎉 Initialization complete!");
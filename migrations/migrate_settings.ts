@@ -0,0 +1,52 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { SplStakingLocked } from "../target/types/spl_staking_locked";
+
+async function main() {
+  // Configure Anchor
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace
+    .SplStakingLocked as Program<SplStakingLocked>;
+
+  console.log("🚚 SPL Staking Locked - Migrate Settings");
+  console.log("=".repeat(40));
+  console.log(`🌐 Network: ${provider.connection.rpcEndpoint}`);
+  console.log(`📋 Program ID: ${program.programId.toString()}`);
+  console.log(`👤 Administrator: ${provider.wallet.publicKey.toString()}`);
+
+  try {
+    console.log("\n⏳ Sending migration transaction...");
+
+    const tx = await program.methods
+      .migrateSettings()
+      .accounts({
+        administrator: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    console.log("✅ Settings migrated successfully!");
+    console.log(`📋 Transaction: ${tx}`);
+    console.log(
+      "⚠️  Call configure_reward_ratio again to replace the truncated v1.0.0 rate with the exact one"
+    );
+  } catch (error) {
+    console.error("\n❌ Migration failed:");
+    if (error instanceof anchor.AnchorError) {
+      console.error(
+        `   Anchor Error (${error.error.errorCode.code}): ${error.error.errorMessage}`
+      );
+    } else if (error.message) {
+      console.error(`   Error: ${error.message}`);
+    } else {
+      console.error("   Unknown error:", error);
+    }
+    process.exit(1);
+  }
+}
+
+main().catch((error) => {
+  console.error("💥 Unexpected error:", error);
+  process.exit(1);
+});
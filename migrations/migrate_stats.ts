@@ -0,0 +1,176 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { SplStakingLocked } from "../target/types/spl_staking_locked";
+import yargs from "yargs";
+import { hideBin } from "yargs/helpers";
+
+// UserInfo layout deployed in v1.0.0
+const USER_INFO_V1_SIZE = 8 + 32 + 8 + 4 + 8 + 8 + 4 + 8 + 8;
+const USER_INFO_V1_STAKE_AMOUNT_OFFSET = 8 + 32;
+const USER_INFO_V1_WITHDRAWAL_REQUEST_AMOUNT_OFFSET = 8 + 32 + 8 + 4 + 8 + 8 + 4;
+const USER_INFO_V1_WITHDRAWAL_REQUEST_REWARD_AMOUNT_OFFSET =
+  USER_INFO_V1_WITHDRAWAL_REQUEST_AMOUNT_OFFSET + 8;
+
+interface MigrateStatsArgs {
+  totalRewardForfeited: string;
+  totalPositionsOpened?: string;
+  lifetimeStaked?: string;
+  lifetimeWithdrawn: string;
+  lifetimeRewardsPaid: string;
+  dryRun: boolean;
+}
+
+async function main() {
+  const argv = (await yargs(hideBin(process.argv))
+    .usage("Usage: $0 [options]")
+    .option("total-reward-forfeited", {
+      type: "string",
+      description: "Sum of all WithdrawnAndForfeitedRewards events",
+      default: "0",
+    })
+    .option("total-positions-opened", {
+      type: "string",
+      description:
+        "Number of positions ever opened. Defaults to the number of user accounts",
+    })
+    .option("lifetime-staked", {
+      type: "string",
+      description:
+        "Sum of all Staked events. Defaults to the tokens currently staked or pending withdrawal",
+    })
+    .option("lifetime-withdrawn", {
+      type: "string",
+      description: "Sum of token amounts of all withdrawal events",
+      default: "0",
+    })
+    .option("lifetime-rewards-paid", {
+      type: "string",
+      description: "Sum of reward amounts of all Withdrawn events",
+      default: "0",
+    })
+    .option("dry-run", {
+      type: "boolean",
+      description: "Only print the computed values",
+      default: false,
+    })
+    .example("$0 --dry-run", "Show the values the Stats account would get")
+    .help("h")
+    .alias("h", "help")
+    .version(false)
+    .strict()
+    .parseAsync()) as MigrateStatsArgs;
+
+  // Configure Anchor
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace
+    .SplStakingLocked as Program<SplStakingLocked>;
+
+  console.log("🚚 SPL Staking Locked - Migrate Stats");
+  console.log("=".repeat(40));
+  console.log(`🌐 Network: ${provider.connection.rpcEndpoint}`);
+  console.log(`📋 Program ID: ${program.programId.toString()}`);
+  console.log(`👤 Administrator: ${provider.wallet.publicKey.toString()}`);
+
+  // Counters, which can be reconstructed from the current user accounts
+  console.log("\n🔍 Scanning user accounts...");
+  const userInfos = await provider.connection.getProgramAccounts(
+    program.programId,
+    { filters: [{ dataSize: USER_INFO_V1_SIZE }] }
+  );
+
+  let activeStakers = 0;
+  let totalStaked = new anchor.BN(0);
+  let totalWithdrawalRequestAmount = new anchor.BN(0);
+  let totalWithdrawalRequestRewardAmount = new anchor.BN(0);
+  for (const { account } of userInfos) {
+    const data = account.data;
+    const stakeAmount = new anchor.BN(
+      data.subarray(
+        USER_INFO_V1_STAKE_AMOUNT_OFFSET,
+        USER_INFO_V1_STAKE_AMOUNT_OFFSET + 8
+      ),
+      "le"
+    );
+    if (!stakeAmount.isZero()) {
+      activeStakers += 1;
+    }
+    totalStaked = totalStaked.add(stakeAmount);
+    totalWithdrawalRequestAmount = totalWithdrawalRequestAmount.add(
+      new anchor.BN(
+        data.subarray(
+          USER_INFO_V1_WITHDRAWAL_REQUEST_AMOUNT_OFFSET,
+          USER_INFO_V1_WITHDRAWAL_REQUEST_AMOUNT_OFFSET + 8
+        ),
+        "le"
+      )
+    );
+    totalWithdrawalRequestRewardAmount = totalWithdrawalRequestRewardAmount.add(
+      new anchor.BN(
+        data.subarray(
+          USER_INFO_V1_WITHDRAWAL_REQUEST_REWARD_AMOUNT_OFFSET,
+          USER_INFO_V1_WITHDRAWAL_REQUEST_REWARD_AMOUNT_OFFSET + 8
+        ),
+        "le"
+      )
+    );
+  }
+
+  // Lifetime counters can only be reconstructed from the history
+  const args = {
+    totalRewardForfeited: new anchor.BN(argv.totalRewardForfeited),
+    totalWithdrawalRequestAmount,
+    totalWithdrawalRequestRewardAmount,
+    activeStakers: new anchor.BN(activeStakers),
+    totalPositionsOpened: new anchor.BN(
+      argv.totalPositionsOpened ?? userInfos.length
+    ),
+    lifetimeStaked: argv.lifetimeStaked
+      ? new anchor.BN(argv.lifetimeStaked)
+      : totalStaked.add(totalWithdrawalRequestAmount),
+    lifetimeWithdrawn: new anchor.BN(argv.lifetimeWithdrawn),
+    lifetimeRewardsPaid: new anchor.BN(argv.lifetimeRewardsPaid),
+  };
+
+  console.log(`   User accounts: ${userInfos.length}`);
+  for (const [key, value] of Object.entries(args)) {
+    console.log(`   ${key}: ${value.toString()}`);
+  }
+
+  if (argv.dryRun) {
+    console.log("\n✅ Dry run complete!");
+    return;
+  }
+
+  try {
+    console.log("\n⏳ Sending migration transaction...");
+
+    const tx = await program.methods
+      .migrateStats(args)
+      .accounts({
+        administrator: provider.wallet.publicKey,
+      })
+      .rpc();
+
+    console.log("✅ Stats migrated successfully!");
+    console.log(`📋 Transaction: ${tx}`);
+  } catch (error) {
+    console.error("\n❌ Migration failed:");
+    if (error instanceof anchor.AnchorError) {
+      console.error(
+        `   Anchor Error (${error.error.errorCode.code}): ${error.error.errorMessage}`
+      );
+    } else if (error.message) {
+      console.error(`   Error: ${error.message}`);
+    } else {
+      console.error("   Unknown error:", error);
+    }
+    process.exit(1);
+  }
+}
+
+main().catch((error) => {
+  console.error("💥 Unexpected error:", error);
+  process.exit(1);
+});
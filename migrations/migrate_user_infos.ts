@@ -0,0 +1,101 @@
+import * as anchor from "@coral-xyz/anchor";
+import { Program } from "@coral-xyz/anchor";
+import { PublicKey } from "@solana/web3.js";
+import { SplStakingLocked } from "../target/types/spl_staking_locked";
+import yargs from "yargs";
+import { hideBin } from "yargs/helpers";
+
+// UserInfo layout deployed in v1.0.0
+const USER_INFO_V1_SIZE = 8 + 32 + 8 + 4 + 8 + 8 + 4 + 8 + 8;
+const USER_INFO_V1_USER_OFFSET = 8;
+
+interface MigrateUserInfosArgs {
+  dryRun: boolean;
+}
+
+async function main() {
+  const argv = (await yargs(hideBin(process.argv))
+    .usage("Usage: $0 [options]")
+    .option("dry-run", {
+      type: "boolean",
+      description: "Only list the accounts to migrate",
+      default: false,
+    })
+    .example("$0 --dry-run", "List the user accounts in the v1.0.0 layout")
+    .help("h")
+    .alias("h", "help")
+    .version(false)
+    .strict()
+    .parseAsync()) as MigrateUserInfosArgs;
+
+  // Configure Anchor
+  const provider = anchor.AnchorProvider.env();
+  anchor.setProvider(provider);
+
+  const program = anchor.workspace
+    .SplStakingLocked as Program<SplStakingLocked>;
+
+  console.log("🚚 SPL Staking Locked - Migrate User Accounts");
+  console.log("=".repeat(40));
+  console.log(`🌐 Network: ${provider.connection.rpcEndpoint}`);
+  console.log(`📋 Program ID: ${program.programId.toString()}`);
+  console.log(`👤 Payer: ${provider.wallet.publicKey.toString()}`);
+
+  console.log("\n🔍 Scanning user accounts...");
+  const userInfos = await provider.connection.getProgramAccounts(
+    program.programId,
+    { filters: [{ dataSize: USER_INFO_V1_SIZE }] }
+  );
+  console.log(`   User accounts to migrate: ${userInfos.length}`);
+
+  let failed = 0;
+  for (const { pubkey, account } of userInfos) {
+    const user = new PublicKey(
+      account.data.subarray(
+        USER_INFO_V1_USER_OFFSET,
+        USER_INFO_V1_USER_OFFSET + 32
+      )
+    );
+
+    if (argv.dryRun) {
+      console.log(`   ${user.toString()} (${pubkey.toString()})`);
+      continue;
+    }
+
+    try {
+      const tx = await program.methods
+        .migrateUserInfo()
+        .accounts({
+          payer: provider.wallet.publicKey,
+          user,
+        })
+        .rpc();
+      console.log(`✅ ${user.toString()}: ${tx}`);
+    } catch (error) {
+      failed += 1;
+      if (error instanceof anchor.AnchorError) {
+        console.error(
+          `❌ ${user.toString()}: Anchor Error (${error.error.errorCode.code}): ${error.error.errorMessage}`
+        );
+      } else {
+        console.error(`❌ ${user.toString()}: ${error.message ?? error}`);
+      }
+    }
+  }
+
+  if (argv.dryRun) {
+    console.log("\n✅ Dry run complete!");
+    return;
+  }
+
+  if (failed > 0) {
+    console.error(`\n❌ ${failed} accounts failed to migrate, run again to retry`);
+    process.exit(1);
+  }
+  console.log("\n✅ All user accounts migrated!");
+}
+
+main().catch((error) => {
+  console.error("💥 Unexpected error:", error);
+  process.exit(1);
+});
@@ -167,10 +167,10 @@ async function main() {
         ).toLocaleString()} tokens`
       );
       const withdrawalTime = new Date(
-        existingUserInfo.withdrawalRequestTime * 1000
+        existingUserInfo.withdrawalRequestTime.toNumber() * 1000
       );
       const delayEnd = new Date(
-        (existingUserInfo.withdrawalRequestTime +
+        (existingUserInfo.withdrawalRequestTime.toNumber() +
           settings.withdrawalDelaySeconds) *
           1000
       );
@@ -180,8 +180,8 @@ async function main() {
       console.log(`   Available to Withdraw: ${delayEnd.toLocaleString()}`);
     }
 
-    if (existingUserInfo.stakedAt > 0) {
-      const stakedTime = new Date(existingUserInfo.stakedAt * 1000);
+    if (existingUserInfo.stakedAt.toNumber() > 0) {
+      const stakedTime = new Date(existingUserInfo.stakedAt.toNumber() * 1000);
       console.log(`   Staking Started: ${stakedTime.toLocaleString()}`);
     }
   } catch (error) {
@@ -262,8 +262,8 @@ async function main() {
       ).toFixed(2)} days)`
     );
 
-    if (userInfoAfter.stakedAt > 0) {
-      const stakedTime = new Date(userInfoAfter.stakedAt * 1000);
+    if (userInfoAfter.stakedAt.toNumber() > 0) {
+      const stakedTime = new Date(userInfoAfter.stakedAt.toNumber() * 1000);
       console.log(`   Staking Started: ${stakedTime.toLocaleString()}`);
     }
 
@@ -58,8 +58,15 @@ async function main() {
       24 *
       60 *
       60;
-    const aprPercentage = yearlyRate / 1e10;
+    const aprPercentage = yearlyRate / 1e25;
     console.log(`📈 Current APR: ${aprPercentage.toFixed(2)}%`);
+    console.log(
+      `🏁 Rewards End Time: ${
+        settings.rewardsEndTime
+          ? new Date(settings.rewardsEndTime.toNumber() * 1000).toLocaleString()
+          : "None"
+      }`
+    );
 
     // Store mint info for later
     mintInfo = await getMint(provider.connection, settings.tokenMint);
@@ -90,7 +97,7 @@ async function main() {
     );
     console.log(
       `⏰ Last Update Time: ${new Date(
-        stats.lastUpdateTime * 1000
+        stats.lastUpdateTime.toNumber() * 1000
       ).toLocaleString()}`
     );
     console.log(
@@ -108,6 +115,11 @@ async function main() {
         Number(stats.totalRewardProvided) / decimalMultiplier
       ).toLocaleString()} tokens`
     );
+    console.log(
+      `🚫 Total Reward Forfeited: ${(
+        Number(stats.totalRewardForfeited) / decimalMultiplier
+      ).toLocaleString()} tokens`
+    );
     const secondsElapsed =
       Math.floor(Date.now() / 1000) - Number(stats.lastUpdateTime);
     const daysElapsed = secondsElapsed / (24 * 60 * 60);
@@ -116,7 +128,8 @@ async function main() {
         1
       )} days) old): ${(
         (Number(stats.totalRewardProvided) -
-          Number(stats.totalRewardPromised)) /
+          Number(stats.totalRewardPromised) +
+          Number(stats.totalRewardForfeited)) /
         decimalMultiplier
       ).toLocaleString()} tokens`
     );
@@ -201,6 +214,41 @@ async function main() {
     } catch (error) {
       console.log(`❌ Could not fetch reward runway: ${error.message}`);
     }
+
+    try {
+      const solvency = await program.methods.viewSolvency().view();
+
+      console.log(
+        `🏦 Reward Liability: ${(
+          Number(solvency.rewardLiability) / decimalMultiplier
+        ).toLocaleString()} tokens`
+      );
+      console.log(
+        `💰 Reward Surplus: ${(
+          Number(solvency.rewardSurplus) / decimalMultiplier
+        ).toLocaleString()} tokens`
+      );
+      console.log(
+        `👛 User Vaults (staked + pending withdrawal): ${(
+          Number(solvency.userVaultAmount) / decimalMultiplier
+        ).toLocaleString()} tokens`
+      );
+      if (
+        solvency.collateralizationRatioNumerator.eq(
+          new anchor.BN("18446744073709551615")
+        )
+      ) {
+        console.log(`📐 Collateralization Ratio: ∞ (no reward liability)`);
+      } else {
+        console.log(
+          `📐 Collateralization Ratio: ${(
+            Number(solvency.collateralizationRatioNumerator) / 1e10
+          ).toFixed(2)}%`
+        );
+      }
+    } catch (error) {
+      console.log(`❌ Could not fetch solvency: ${error.message}`);
+    }
   } catch (error) {
     console.log(
       `⚠️  Could not fetch protocol health metrics: ${error.message}`
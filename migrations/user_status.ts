@@ -78,8 +78,8 @@ async function main() {
     );
     console.log(
       `⏰ Staked At: ${
-        userInfo.stakedAt
-          ? new Date(userInfo.stakedAt * 1000).toLocaleString()
+        userInfo.stakedAt.toNumber()
+          ? new Date(userInfo.stakedAt.toNumber() * 1000).toLocaleString()
           : "Never"
       }`
     );
@@ -100,8 +100,8 @@ async function main() {
     );
     console.log(
       `⏰ Withdrawal Request Time: ${
-        userInfo.withdrawalRequestTime
-          ? new Date(userInfo.withdrawalRequestTime * 1000).toLocaleString()
+        userInfo.withdrawalRequestTime.toNumber()
+          ? new Date(userInfo.withdrawalRequestTime.toNumber() * 1000).toLocaleString()
           : "None"
       }`
     );
@@ -113,10 +113,7 @@ async function main() {
   // Call view_current_rewards
   try {
     const rewards = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user,
-      })
+      .viewCurrentRewards(user)
       .view();
     console.log("-".repeat(30));
     console.log(
@@ -1,5 +1,7 @@
 #![allow(unexpected_cfgs)]
 use anchor_lang::prelude::*;
+use anchor_lang::solana_program::program_option::COption;
+use anchor_lang::system_program;
 use anchor_spl::associated_token::AssociatedToken;
 use anchor_spl::token::{self, Mint, Token, TokenAccount, Transfer};
 use solana_security_txt::security_txt;
@@ -35,43 +37,82 @@ pub mod spl_staking_locked {
         );
 
         let administrator = &ctx.accounts.administrator;
+        let _program_data = &ctx.accounts.program_data;
         let settings = &mut ctx.accounts.settings;
         let stats = &mut ctx.accounts.stats;
+        let rate_history = &mut ctx.accounts.rate_history;
         let token_mint = &ctx.accounts.token_mint;
         let _system_program = &ctx.accounts.system_program;
 
         // Convert yearly percentage to per-second per-token rate
         let reward_rate_per_second_per_token_numerator =
-            reward_rate_yearly_percentage_numerator / SECONDS_PER_YEAR;
+            calculate_reward_rate_per_second_per_token_numerator(
+                reward_rate_yearly_percentage_numerator,
+            );
 
+        settings.version = Settings::VERSION;
         settings.administrator = administrator.key();
         settings.token_mint = token_mint.key();
-        settings.withdrawal_delay_seconds = (withdrawal_delay_days * SECONDS_PER_DAY) as u32;
+        settings.withdrawal_delay_seconds = calculate_seconds_from_days(withdrawal_delay_days)?;
         settings.reward_rate_per_second_per_token_numerator =
             reward_rate_per_second_per_token_numerator;
         settings.pending_administrator = None;
-
+        settings.rewards_end_time = None;
+        settings.solvency_mode_enabled = false;
+        settings.partial_reward_payout_enabled = false;
+        settings.voter_weight_age_bonus_numerator = 0;
+        settings.voter_weight_age_bonus_saturation_seconds = 0;
+        settings.loyalty_tiers = Vec::new();
+        settings.previous_loyalty_tiers = Vec::new();
+
+        stats.version = Stats::VERSION;
         stats.reward_per_token_stored_numerator = 0;
-        stats.last_update_time = Clock::get()?.unix_timestamp as u32;
+        stats.last_update_time = Clock::get()?.unix_timestamp;
         stats.total_staked = 0;
         stats.total_reward_promised = 0;
         stats.total_reward_provided = 0;
+        stats.total_reward_debt = 0;
+        stats.total_reward_forfeited = 0;
+        stats.total_withdrawal_request_amount = 0;
+        stats.total_withdrawal_request_reward_amount = 0;
+        stats.active_stakers = 0;
+        stats.total_positions_opened = 0;
+        stats.lifetime_staked = 0;
+        stats.lifetime_withdrawn = 0;
+        stats.lifetime_rewards_paid = 0;
+        stats.event_sequence = 0;
+        stats.loyalty_tier_staked = [0; MAX_LOYALTY_TIERS];
+        stats.loyalty_reward_per_token_stored_numerator = [0; MAX_LOYALTY_TIERS];
+        // Epoch 0 is the empty schedule before the first one, see migrate_user_info
+        stats.loyalty_schedule_epoch = 1;
+        stats.loyalty_schedule_start_time = stats.last_update_time;
+        stats.previous_loyalty_reward_per_token_stored_numerator = [0; MAX_LOYALTY_TIERS];
+
+        // The history starts with the initial rate, so every rate since the deployment is on record
+        rate_history.checkpoints = vec![RateCheckpoint {
+            timestamp: stats.last_update_time,
+            old_reward_rate_per_second_per_token_numerator:
+                reward_rate_per_second_per_token_numerator,
+            new_reward_rate_per_second_per_token_numerator:
+                reward_rate_per_second_per_token_numerator,
+            reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+        }];
 
         let event = Initialized {
             administrator: administrator.key(),
             token_mint: token_mint.key(),
             withdrawal_delay_seconds: settings.withdrawal_delay_seconds,
             reward_rate_yearly_percentage_numerator,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
 
     pub fn add_rewards(ctx: Context<AddRewardsAccounts>, amount: u64) -> Result<()> {
         let administrator = &ctx.accounts.administrator;
-        let _settings = &ctx.accounts.settings;
+        let settings = &ctx.accounts.settings;
         let stats = &mut ctx.accounts.stats;
         let admin_token_account = &ctx.accounts.admin_token_account;
         let protocol_token_account = &ctx.accounts.protocol_token_account;
@@ -81,8 +122,14 @@ pub mod spl_staking_locked {
 
         require!(amount > 0, StakingError::InvalidAmount);
 
+        // Settle accrual before the pool grows, so that solvency mode does not pay for the halted period
+        update_accumulators(settings, stats)?;
+
         // Update total reward provided
-        stats.total_reward_provided += amount;
+        stats.total_reward_provided = stats
+            .total_reward_provided
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
 
         token::transfer(
             CpiContext::new(
@@ -99,9 +146,9 @@ pub mod spl_staking_locked {
         let event = RewardsAdded {
             administrator: administrator.key(),
             amount,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -113,24 +160,53 @@ pub mod spl_staking_locked {
         let administrator = &ctx.accounts.administrator;
         let settings = &mut ctx.accounts.settings;
         let stats = &mut ctx.accounts.stats;
+        let rate_history = &mut ctx.accounts.rate_history;
+        let system_program = &ctx.accounts.system_program;
 
         // Update both accumulators before changing the rate
         update_accumulators(settings, stats)?;
 
         // Convert yearly percentage to per-second per-token rate
         let new_reward_rate_per_second_per_token_numerator =
-            new_reward_rate_yearly_percentage_numerator / SECONDS_PER_YEAR;
+            calculate_reward_rate_per_second_per_token_numerator(
+                new_reward_rate_yearly_percentage_numerator,
+            );
 
+        let old_reward_rate_per_second_per_token_numerator =
+            settings.reward_rate_per_second_per_token_numerator;
         settings.reward_rate_per_second_per_token_numerator =
             new_reward_rate_per_second_per_token_numerator;
 
+        // The history grows by one checkpoint with every change. The administrator covers the rent.
+        resize_account(
+            &rate_history.to_account_info(),
+            administrator,
+            system_program,
+            RateHistory::space(rate_history.checkpoints.len() + 1),
+        )?;
+        rate_history.checkpoints.push(RateCheckpoint {
+            timestamp: stats.last_update_time,
+            old_reward_rate_per_second_per_token_numerator,
+            new_reward_rate_per_second_per_token_numerator,
+            reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+        });
+
+        // The new rate applies from the accumulators update on
         let event = RewardRatioConfigured {
             administrator: administrator.key(),
+            old_reward_rate_yearly_percentage_numerator:
+                calculate_reward_rate_yearly_percentage_numerator(
+                    old_reward_rate_per_second_per_token_numerator,
+                )?,
+            old_reward_rate_per_second_per_token_numerator,
             new_reward_rate_yearly_percentage_numerator,
             new_reward_rate_per_second_per_token_numerator,
+            effective_time: stats.last_update_time,
+            reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+            total_reward_promised: stats.total_reward_promised,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -141,20 +217,109 @@ pub mod spl_staking_locked {
     ) -> Result<()> {
         let administrator = &ctx.accounts.administrator;
         let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
 
         require!(
             new_withdrawal_delay_days <= MAX_WITHDRAWAL_DELAY_DAYS,
             StakingError::InvalidAmount
         );
 
-        settings.withdrawal_delay_seconds = (new_withdrawal_delay_days * SECONDS_PER_DAY) as u32;
+        let old_withdrawal_delay_seconds = settings.withdrawal_delay_seconds;
+        settings.withdrawal_delay_seconds = calculate_seconds_from_days(new_withdrawal_delay_days)?;
 
+        // Applies immediately, to the pending withdrawal requests as well
+        let current_time = Clock::get()?.unix_timestamp;
         let event = WithdrawalDelayConfigured {
             administrator: administrator.key(),
+            old_withdrawal_delay_seconds,
             new_withdrawal_delay_seconds: settings.withdrawal_delay_seconds,
+            effective_time: current_time,
+            reward_per_token_stored_numerator: calculate_current_reward_per_token_stored_numerator(
+                settings,
+                stats,
+                current_time,
+            )?,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn configure_rewards_end_time(
+        ctx: Context<ConfigureRewardsEndTimeAccounts>,
+        new_rewards_end_time: Option<i64>, // None to accrue rewards indefinitely
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+
+        // Settle accrual under the current end time before changing it
+        update_accumulators(settings, stats)?;
+
+        let old_rewards_end_time = settings.rewards_end_time;
+        settings.rewards_end_time = new_rewards_end_time;
+
+        // The new end time applies from the accumulators update on
+        let event = RewardsEndTimeConfigured {
+            administrator: administrator.key(),
+            old_rewards_end_time,
+            new_rewards_end_time,
+            effective_time: stats.last_update_time,
+            reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+            total_reward_promised: stats.total_reward_promised,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn configure_solvency_mode(
+        ctx: Context<ConfigureSolvencyModeAccounts>,
+        enabled: bool,
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+
+        // Settle accrual under the current mode before changing it
+        update_accumulators(settings, stats)?;
+
+        let old_enabled = settings.solvency_mode_enabled;
+        settings.solvency_mode_enabled = enabled;
+
+        // The new mode applies from the accumulators update on
+        let event = SolvencyModeConfigured {
+            administrator: administrator.key(),
+            old_enabled,
+            new_enabled: enabled,
+            effective_time: stats.last_update_time,
+            reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+            total_reward_promised: stats.total_reward_promised,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn configure_partial_reward_payout(
+        ctx: Context<ConfigurePartialRewardPayoutAccounts>,
+        enabled: bool,
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+
+        settings.partial_reward_payout_enabled = enabled;
+
+        let event = PartialRewardPayoutConfigured {
+            administrator: administrator.key(),
+            enabled,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -165,15 +330,26 @@ pub mod spl_staking_locked {
     ) -> Result<()> {
         let administrator = &ctx.accounts.administrator;
         let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
 
+        // A transfer initiated earlier is replaced
+        let old_pending_administrator = settings.pending_administrator;
         settings.pending_administrator = Some(new_administrator);
 
+        let current_time = Clock::get()?.unix_timestamp;
         let event = OwnershipTransferInitiated {
             current_administrator: administrator.key(),
+            old_pending_administrator,
             new_administrator,
+            effective_time: current_time,
+            reward_per_token_stored_numerator: calculate_current_reward_per_token_stored_numerator(
+                settings,
+                stats,
+                current_time,
+            )?,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -183,6 +359,7 @@ pub mod spl_staking_locked {
     ) -> Result<()> {
         let new_administrator = &ctx.accounts.new_administrator;
         let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
 
         require!(
             settings.pending_administrator == Some(new_administrator.key()),
@@ -196,9 +373,260 @@ pub mod spl_staking_locked {
         let event = OwnershipTransferFinalized {
             old_administrator,
             new_administrator: settings.administrator,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn configure_reward_stream(
+        ctx: Context<ConfigureRewardStreamAccounts>,
+        rate_per_second: u64,
+        cap: u64,
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let _settings = &ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+        let reward_stream = &mut ctx.accounts.reward_stream;
+        let treasury_token_account = &ctx.accounts.treasury_token_account;
+        let _system_program = &ctx.accounts.system_program;
+
+        // Funding accrued under the previous configuration, but not pulled yet, is dropped.
+        reward_stream.treasury_token_account = treasury_token_account.key();
+        reward_stream.rate_per_second = rate_per_second;
+        reward_stream.cap = cap;
+        reward_stream.last_pull_time = Clock::get()?.unix_timestamp;
+
+        let event = RewardStreamConfigured {
+            administrator: administrator.key(),
+            treasury_token_account: reward_stream.treasury_token_account,
+            rate_per_second,
+            cap,
+            total_pulled: reward_stream.total_pulled,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn configure_voter_weight_age_bonus(
+        ctx: Context<ConfigureVoterWeightAgeBonusAccounts>,
+        age_bonus_numerator: u64, // e.g., 500_000_000_000 for +50% voting power
+        saturation_days: u64,
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+
+        settings.voter_weight_age_bonus_numerator = age_bonus_numerator;
+        settings.voter_weight_age_bonus_saturation_seconds =
+            calculate_seconds_from_days(saturation_days)?;
+
+        let event = VoterWeightAgeBonusConfigured {
+            administrator: administrator.key(),
+            age_bonus_numerator,
+            saturation_seconds: settings.voter_weight_age_bonus_saturation_seconds,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn configure_loyalty_schedule(
+        ctx: Context<ConfigureLoyaltyScheduleAccounts>,
+        tiers: Vec<LoyaltyTierArgs>, // e.g., +1% APR after 90 days and +2% after 180 days
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &mut ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+
+        require!(
+            tiers.len() <= MAX_LOYALTY_TIERS,
+            StakingError::InvalidLoyaltySchedule
+        );
+
+        let mut new_loyalty_tiers = Vec::with_capacity(tiers.len());
+        let mut previous_min_staked_seconds = 0;
+        for tier in &tiers {
+            let min_staked_seconds = calculate_seconds_from_days(tier.min_staked_days)?;
+            // Ordered by the stake age, so the last reached tier is the one that applies
+            require!(
+                min_staked_seconds > previous_min_staked_seconds,
+                StakingError::InvalidLoyaltySchedule
+            );
+            previous_min_staked_seconds = min_staked_seconds;
+
+            new_loyalty_tiers.push(LoyaltyTier {
+                min_staked_seconds,
+                bonus_rate_per_second_per_token_numerator:
+                    calculate_reward_rate_per_second_per_token_numerator(
+                        tier.bonus_rate_yearly_percentage_numerator,
+                    ),
+            });
+        }
+
+        // Settle the bonus under the current schedule before changing it
+        update_accumulators(settings, stats)?;
+
+        let old_loyalty_tiers = replace_loyalty_schedule(settings, stats, new_loyalty_tiers)?;
+
+        // The new schedule applies from the accumulators update on
+        let event = LoyaltyScheduleConfigured {
+            administrator: administrator.key(),
+            old_loyalty_tiers,
+            new_loyalty_tiers: settings.loyalty_tiers.clone(),
+            loyalty_schedule_epoch: stats.loyalty_schedule_epoch,
+            effective_time: stats.last_update_time,
+            loyalty_reward_per_token_stored_numerator: stats
+                .loyalty_reward_per_token_stored_numerator,
+            total_reward_promised: stats.total_reward_promised,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn migrate_stats(
+        ctx: Context<MigrateStatsAccounts>,
+        args: StatsMigrationArgs,
+    ) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let total_stake_history = &mut ctx.accounts.total_stake_history;
+        let system_program = &ctx.accounts.system_program;
+
+        // Settings may still be in an old layout as well, so the administrator is read manually
+        require_keys_eq!(
+            read_administrator(settings)?,
+            administrator.key(),
+            anchor_lang::error::ErrorCode::ConstraintHasOne
+        );
+
+        let stats_v1 = match read_account_version::<Stats>(stats, StatsV1::SPACE)? {
+            1 => StatsV1::deserialize(&mut &stats.try_borrow_data()?[8..])?,
+            _ => return err!(StakingError::AccountAlreadyMigrated),
+        };
+
+        // Fields missing from the old layout are either new features, or can only be reconstructed off-chain
+        let mut migrated_stats = Stats {
+            version: Stats::VERSION,
+            reward_per_token_stored_numerator: scale_v1_reward_numerator(
+                stats_v1.reward_per_token_stored_numerator,
+            ),
+            last_update_time: stats_v1.last_update_time as i64,
+            total_staked: stats_v1.total_staked,
+            total_reward_promised: stats_v1.total_reward_promised,
+            total_reward_provided: stats_v1.total_reward_provided,
+            total_reward_debt: 0,
+            total_reward_forfeited: args.total_reward_forfeited,
+            total_withdrawal_request_amount: args.total_withdrawal_request_amount,
+            total_withdrawal_request_reward_amount: args.total_withdrawal_request_reward_amount,
+            active_stakers: args.active_stakers,
+            total_positions_opened: args.total_positions_opened,
+            lifetime_staked: args.lifetime_staked,
+            lifetime_withdrawn: args.lifetime_withdrawn,
+            lifetime_rewards_paid: args.lifetime_rewards_paid,
+            event_sequence: 0,
+            // Positions join their loyalty tiers once migrated
+            loyalty_tier_staked: [0; MAX_LOYALTY_TIERS],
+            loyalty_reward_per_token_stored_numerator: [0; MAX_LOYALTY_TIERS],
+            // Epoch 0 is the empty schedule before the first one, see migrate_user_info
+            loyalty_schedule_epoch: 1,
+            loyalty_schedule_start_time: stats_v1.last_update_time as i64,
+            previous_loyalty_reward_per_token_stored_numerator: [0; MAX_LOYALTY_TIERS],
+        };
+        let metadata = next_event_metadata(&mut migrated_stats)?;
+
+        resize_account(stats, administrator, system_program, 8 + Stats::INIT_SPACE)?;
+        migrated_stats.try_serialize(&mut &mut stats.try_borrow_mut_data()?[..])?;
+
+        // The history starts at the migration, earlier balances are not known on-chain
+        push_stake_checkpoint(
+            total_stake_history,
+            administrator,
+            system_program,
+            MAX_TOTAL_STAKE_CHECKPOINTS,
+            migrated_stats.total_staked,
+        )?;
+
+        let event = StatsMigrated {
+            administrator: administrator.key(),
+            args,
+            metadata,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn migrate_settings(ctx: Context<MigrateSettingsAccounts>) -> Result<()> {
+        let administrator = &ctx.accounts.administrator;
+        let settings = &ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+        let rate_history = &mut ctx.accounts.rate_history;
+        let system_program = &ctx.accounts.system_program;
+
+        require_keys_eq!(
+            read_administrator(settings)?,
+            administrator.key(),
+            anchor_lang::error::ErrorCode::ConstraintHasOne
+        );
+
+        let settings_v1 = match read_account_version::<Settings>(settings, SettingsV1::SPACE)? {
+            1 => SettingsV1::deserialize(&mut &settings.try_borrow_data()?[8..])?,
+            _ => return err!(StakingError::AccountAlreadyMigrated),
+        };
+
+        // The v1 rate was already truncated. Reconfigure the reward ratio to get the exact rate.
+        let migrated_settings = Settings {
+            version: Settings::VERSION,
+            administrator: settings_v1.administrator,
+            pending_administrator: settings_v1.pending_administrator,
+            token_mint: settings_v1.token_mint,
+            withdrawal_delay_seconds: settings_v1.withdrawal_delay_seconds,
+            reward_rate_per_second_per_token_numerator: scale_v1_reward_numerator(
+                settings_v1.reward_rate_per_second_per_token_numerator,
+            ),
+            rewards_end_time: None,
+            solvency_mode_enabled: false,
+            partial_reward_payout_enabled: false,
+            voter_weight_age_bonus_numerator: 0,
+            voter_weight_age_bonus_saturation_seconds: 0,
+            loyalty_tiers: Vec::new(),
+            previous_loyalty_tiers: Vec::new(),
+        };
+
+        resize_account(
+            settings,
+            administrator,
+            system_program,
+            8 + Settings::INIT_SPACE,
+        )?;
+        migrated_settings.try_serialize(&mut &mut settings.try_borrow_mut_data()?[..])?;
+
+        // The history starts with the migrated rate, which applied up to the accumulators update time.
+        // The migration does not change the rate, only its precision.
+        rate_history.checkpoints = vec![RateCheckpoint {
+            timestamp: stats.last_update_time,
+            old_reward_rate_per_second_per_token_numerator: migrated_settings
+                .reward_rate_per_second_per_token_numerator,
+            new_reward_rate_per_second_per_token_numerator: migrated_settings
+                .reward_rate_per_second_per_token_numerator,
+            reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+        }];
+
+        let event = SettingsMigrated {
+            administrator: administrator.key(),
+            reward_rate_per_second_per_token_numerator: migrated_settings
+                .reward_rate_per_second_per_token_numerator,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -215,15 +643,19 @@ pub mod spl_staking_locked {
         let user_token_account = &ctx.accounts.user_token_account;
         let user_info_token_account = &ctx.accounts.user_info_token_account;
         let _token_mint = &ctx.accounts.token_mint;
+        let user_stake_history = &mut ctx.accounts.user_stake_history;
+        let total_stake_history = &mut ctx.accounts.total_stake_history;
         let token_program = &ctx.accounts.token_program;
         let _associated_token_program = &ctx.accounts.associated_token_program;
-        let _system_program = &ctx.accounts.system_program;
+        let system_program = &ctx.accounts.system_program;
 
         require!(amount > 0, StakingError::InvalidAmount);
 
         if user_info.user == Pubkey::default() {
             // Initialize a new user
+            user_info.version = UserInfo::VERSION;
             user_info.user = user.key();
+            user_info.bump = ctx.bumps.user_info;
 
             // Update accumulators and set the reward paid. Ensures that the user is not getting any unfair rewards.
             update_accumulators(settings, stats)?;
@@ -232,14 +664,45 @@ pub mod spl_staking_locked {
             capture_rewards(settings, stats, user_info)?;
         }
 
+        let previous_stake_amount = user_info.stake_amount;
+
         // Check existing stake amount to see if we need to reset the staked_at timestamp
-        if user_info.stake_amount == 0 {
-            user_info.staked_at = Clock::get()?.unix_timestamp as u32;
+        if user_info.stake_amount > 0 {
+            // Extending a position. The added tokens start with no age, so they can not inherit the age of the position.
+            user_info.staked_at =
+                calculate_weighted_staked_at(user_info, amount, Clock::get()?.unix_timestamp)?;
+        } else {
+            user_info.staked_at = Clock::get()?.unix_timestamp;
+
+            // A new position is opened
+            stats.active_stakers = stats
+                .active_stakers
+                .checked_add(1)
+                .ok_or(StakingError::MathOverflow)?;
+            stats.total_positions_opened = stats
+                .total_positions_opened
+                .checked_add(1)
+                .ok_or(StakingError::MathOverflow)?;
         }
-        // If user already has stake_amount > 0, don't change staked_at (extending position)
 
-        user_info.stake_amount += amount;
-        stats.total_staked += amount;
+        user_info.stake_amount = user_info
+            .stake_amount
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.total_staked = stats
+            .total_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        update_position_loyalty_tier(settings, stats, user_info, previous_stake_amount)?;
+
+        user_info.lifetime_staked = user_info
+            .lifetime_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.lifetime_staked = stats
+            .lifetime_staked
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
 
         token::transfer(
             CpiContext::new(
@@ -253,13 +716,28 @@ pub mod spl_staking_locked {
             amount,
         )?;
 
+        push_stake_checkpoint(
+            user_stake_history,
+            user,
+            system_program,
+            MAX_USER_STAKE_CHECKPOINTS,
+            user_info.stake_amount,
+        )?;
+        push_stake_checkpoint(
+            total_stake_history,
+            user,
+            system_program,
+            MAX_TOTAL_STAKE_CHECKPOINTS,
+            stats.total_staked,
+        )?;
+
         let event = Staked {
             user: user.key(),
             amount,
             total_user_staked: user_info.stake_amount,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -269,6 +747,9 @@ pub mod spl_staking_locked {
         let settings = &ctx.accounts.settings;
         let stats = &mut ctx.accounts.stats;
         let user_info = &mut ctx.accounts.user_info;
+        let user_stake_history = &mut ctx.accounts.user_stake_history;
+        let total_stake_history = &mut ctx.accounts.total_stake_history;
+        let system_program = &ctx.accounts.system_program;
 
         require!(user_info.stake_amount > 0, StakingError::NoStakeFound);
 
@@ -278,13 +759,48 @@ pub mod spl_staking_locked {
         let original_reward_amount = user_info.captured_reward;
 
         user_info.stake_amount = 0;
-        stats.total_staked -= original_stake_amount;
+        stats.total_staked = stats
+            .total_staked
+            .checked_sub(original_stake_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        // Statistics only, a migrated value that is too low must not block the exit
+        stats.active_stakers = stats.active_stakers.saturating_sub(1);
         user_info.staked_at = 0;
         user_info.captured_reward = 0;
-
-        user_info.withdrawal_request_time = Clock::get()?.unix_timestamp as u32;
-        user_info.withdrawal_request_amount += original_stake_amount;
-        user_info.withdrawal_request_reward_amount += original_reward_amount;
+        update_position_loyalty_tier(settings, stats, user_info, original_stake_amount)?;
+
+        user_info.withdrawal_request_time = Clock::get()?.unix_timestamp;
+        user_info.withdrawal_request_amount = user_info
+            .withdrawal_request_amount
+            .checked_add(original_stake_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_info.withdrawal_request_reward_amount = user_info
+            .withdrawal_request_reward_amount
+            .checked_add(original_reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.total_withdrawal_request_amount = stats
+            .total_withdrawal_request_amount
+            .checked_add(original_stake_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.total_withdrawal_request_reward_amount = stats
+            .total_withdrawal_request_reward_amount
+            .checked_add(original_reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        push_stake_checkpoint(
+            user_stake_history,
+            user,
+            system_program,
+            MAX_USER_STAKE_CHECKPOINTS,
+            user_info.stake_amount,
+        )?;
+        push_stake_checkpoint(
+            total_stake_history,
+            user,
+            system_program,
+            MAX_TOTAL_STAKE_CHECKPOINTS,
+            stats.total_staked,
+        )?;
 
         let event = WithdrawalRequested {
             user: user.key(),
@@ -293,9 +809,9 @@ pub mod spl_staking_locked {
             added_reward_amount: original_reward_amount,
             total_reward_amount: user_info.withdrawal_request_reward_amount,
             withdrawal_request_time: user_info.withdrawal_request_time,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -303,6 +819,7 @@ pub mod spl_staking_locked {
     pub fn withdraw(ctx: Context<WithdrawAccounts>) -> Result<()> {
         let user = &ctx.accounts.user;
         let settings = &ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
         let user_info = &mut ctx.accounts.user_info;
         let user_token_account = &ctx.accounts.user_token_account;
         let user_info_token_account = &ctx.accounts.user_info_token_account;
@@ -317,19 +834,30 @@ pub mod spl_staking_locked {
             StakingError::NoWithdrawalRequest
         );
 
+        let withdrawal_unlock_time = user_info
+            .withdrawal_request_time
+            .checked_add(settings.withdrawal_delay_seconds as i64)
+            .ok_or(StakingError::MathOverflow)?;
         require!(
-            Clock::get()?.unix_timestamp as u32
-                >= user_info.withdrawal_request_time + settings.withdrawal_delay_seconds,
+            Clock::get()?.unix_timestamp >= withdrawal_unlock_time,
             StakingError::WithdrawalDelayNotMet
         );
 
         require!(
-            protocol_token_account.amount >= user_info.withdrawal_request_reward_amount,
+            protocol_token_account.amount >= user_info.withdrawal_request_reward_amount
+                || settings.partial_reward_payout_enabled,
             StakingError::InsufficientRewards
         );
 
         let token_amount = user_info.withdrawal_request_amount;
-        let reward_amount = user_info.withdrawal_request_reward_amount;
+        // If the pool is short, pay whatever is available. The rest becomes a debt, claimable later.
+        let reward_amount = user_info
+            .withdrawal_request_reward_amount
+            .min(protocol_token_account.amount);
+        let unpaid_reward_amount = user_info
+            .withdrawal_request_reward_amount
+            .checked_sub(reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
 
         // Transfer staked tokens back to user
         if token_amount > 0 {
@@ -365,17 +893,52 @@ pub mod spl_staking_locked {
             )?;
         }
 
+        // Statistics only, migrated values that are too low must not block the exit
+        stats.total_withdrawal_request_amount = stats
+            .total_withdrawal_request_amount
+            .saturating_sub(token_amount);
+        stats.total_withdrawal_request_reward_amount = stats
+            .total_withdrawal_request_reward_amount
+            .saturating_sub(user_info.withdrawal_request_reward_amount);
+
         user_info.withdrawal_request_reward_amount = 0;
         user_info.withdrawal_request_amount = 0;
         user_info.withdrawal_request_time = 0;
 
+        user_info.reward_debt_amount = user_info
+            .reward_debt_amount
+            .checked_add(unpaid_reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.total_reward_debt = stats
+            .total_reward_debt
+            .checked_add(unpaid_reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        user_info.lifetime_withdrawn = user_info
+            .lifetime_withdrawn
+            .checked_add(token_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_info.lifetime_rewards_paid = user_info
+            .lifetime_rewards_paid
+            .checked_add(reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.lifetime_withdrawn = stats
+            .lifetime_withdrawn
+            .checked_add(token_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.lifetime_rewards_paid = stats
+            .lifetime_rewards_paid
+            .checked_add(reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+
         let event = Withdrawn {
             user: user.key(),
             token_amount,
             reward_amount,
+            unpaid_reward_amount,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
@@ -385,6 +948,7 @@ pub mod spl_staking_locked {
     ) -> Result<()> {
         let user = &ctx.accounts.user;
         let settings = &ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
         let user_info = &mut ctx.accounts.user_info;
         let user_token_account = &ctx.accounts.user_token_account;
         let user_info_token_account = &ctx.accounts.user_info_token_account;
@@ -396,9 +960,12 @@ pub mod spl_staking_locked {
             StakingError::NoWithdrawalRequest
         );
 
+        let withdrawal_unlock_time = user_info
+            .withdrawal_request_time
+            .checked_add(settings.withdrawal_delay_seconds as i64)
+            .ok_or(StakingError::MathOverflow)?;
         require!(
-            Clock::get()?.unix_timestamp as u32
-                >= user_info.withdrawal_request_time + settings.withdrawal_delay_seconds,
+            Clock::get()?.unix_timestamp >= withdrawal_unlock_time,
             StakingError::WithdrawalDelayNotMet
         );
 
@@ -427,212 +994,1965 @@ pub mod spl_staking_locked {
             )?;
         }
 
+        // Statistics only, migrated values that are too low must not block the exit
+        stats.total_withdrawal_request_amount = stats
+            .total_withdrawal_request_amount
+            .saturating_sub(token_amount);
+        stats.total_withdrawal_request_reward_amount = stats
+            .total_withdrawal_request_reward_amount
+            .saturating_sub(forfeited_reward_amount);
+
         user_info.withdrawal_request_reward_amount = 0;
         user_info.withdrawal_request_amount = 0;
         user_info.withdrawal_request_time = 0;
 
+        // Forfeited rewards are no longer a liability and become available to other stakers
+        stats.total_reward_forfeited = stats
+            .total_reward_forfeited
+            .checked_add(forfeited_reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        user_info.lifetime_withdrawn = user_info
+            .lifetime_withdrawn
+            .checked_add(token_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_info.lifetime_rewards_forfeited = user_info
+            .lifetime_rewards_forfeited
+            .checked_add(forfeited_reward_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.lifetime_withdrawn = stats
+            .lifetime_withdrawn
+            .checked_add(token_amount)
+            .ok_or(StakingError::MathOverflow)?;
+
         let event = WithdrawnAndForfeitedRewards {
             user: user.key(),
             token_amount,
             forfeited_reward_amount,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
         Ok(())
     }
 
-    pub fn view_current_rewards(ctx: Context<ViewCurrentRewardsAccounts>) -> Result<u64> {
+    pub fn claim_reward_debt(ctx: Context<ClaimRewardDebtAccounts>) -> Result<()> {
         let user = &ctx.accounts.user;
         let settings = &ctx.accounts.settings;
-        let stats = &ctx.accounts.stats;
-        let user_info = &ctx.accounts.user_info;
+        let stats = &mut ctx.accounts.stats;
+        let user_info = &mut ctx.accounts.user_info;
+        let user_token_account = &ctx.accounts.user_token_account;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+        let token_program = &ctx.accounts.token_program;
 
-        // Calculate current reward_per_token_stored_numerator without updating state
-        let time_elapsed = Clock::get()?.unix_timestamp as u32 - stats.last_update_time;
-        let current_reward_per_token_stored_numerator = stats.reward_per_token_stored_numerator
-            + settings.reward_rate_per_second_per_token_numerator * time_elapsed as u64;
+        require!(user_info.reward_debt_amount > 0, StakingError::NoRewardDebt);
 
-        let uncaptured_reward = calculate_uncaptured_rewards(
-            user_info.stake_amount,
-            current_reward_per_token_stored_numerator,
-            user_info.reward_per_token_paid_numerator,
-        );
-        let total_reward = user_info.captured_reward + uncaptured_reward;
+        // Partial claims are allowed, the rest stays as a debt
+        let amount = user_info
+            .reward_debt_amount
+            .min(protocol_token_account.amount);
 
-        let event = CurrentRewardsViewed {
+        require!(amount > 0, StakingError::InsufficientRewards);
+
+        let signer: &[&[&[u8]]] = &[&[b"settings", &[ctx.bumps.settings]]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                Transfer {
+                    from: protocol_token_account.to_account_info(),
+                    to: user_token_account.to_account_info(),
+                    authority: settings.to_account_info(),
+                },
+                signer,
+            ),
+            amount,
+        )?;
+
+        user_info.reward_debt_amount = user_info
+            .reward_debt_amount
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.total_reward_debt = stats
+            .total_reward_debt
+            .checked_sub(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        user_info.lifetime_rewards_paid = user_info
+            .lifetime_rewards_paid
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.lifetime_rewards_paid = stats
+            .lifetime_rewards_paid
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        let event = RewardDebtClaimed {
             user: user.key(),
-            captured_reward: user_info.captured_reward,
-            uncaptured_reward,
-            total_reward,
+            amount,
+            remaining_reward_debt_amount: user_info.reward_debt_amount,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
-        Ok(total_reward)
+        Ok(())
     }
 
-    pub fn view_unallocated_rewards(ctx: Context<ViewUnallocatedRewardsAccounts>) -> Result<i128> {
+    pub fn donate_rewards(ctx: Context<DonateRewardsAccounts>, amount: u64) -> Result<()> {
+        let donor = &ctx.accounts.donor;
         let settings = &ctx.accounts.settings;
-        let stats = &ctx.accounts.stats;
+        let stats = &mut ctx.accounts.stats;
+        let donor_token_account = &ctx.accounts.donor_token_account;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+        let token_program = &ctx.accounts.token_program;
 
-        let unallocated_rewards = calculate_unallocated_rewards(settings, stats)?;
+        require!(amount > 0, StakingError::InvalidAmount);
 
-        let event = UnallocatedRewardsViewed {
-            unallocated_rewards,
+        update_accumulators(settings, stats)?;
+
+        // Donations are indistinguishable from admin provided rewards in the accounting
+        stats.total_reward_provided = stats
+            .total_reward_provided
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        token::transfer(
+            CpiContext::new(
+                token_program.to_account_info(),
+                Transfer {
+                    from: donor_token_account.to_account_info(),
+                    to: protocol_token_account.to_account_info(),
+                    authority: donor.to_account_info(),
+                },
+            ),
+            amount,
+        )?;
+
+        let event = RewardsDonated {
+            donor: donor.key(),
+            amount,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
-        Ok(unallocated_rewards)
+        Ok(())
     }
 
-    pub fn view_reward_runway(ctx: Context<ViewRewardRunwayAccounts>) -> Result<u64> {
+    pub fn pull_reward_stream(ctx: Context<PullRewardStreamAccounts>) -> Result<()> {
         let settings = &ctx.accounts.settings;
-        let stats = &ctx.accounts.stats;
+        let stats = &mut ctx.accounts.stats;
+        let reward_stream = &mut ctx.accounts.reward_stream;
+        let treasury_token_account = &ctx.accounts.treasury_token_account;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+        let token_program = &ctx.accounts.token_program;
+
+        require!(
+            treasury_token_account.delegate == COption::Some(settings.key()),
+            StakingError::RewardStreamNotApproved
+        );
 
-        // Calculate available rewards using helper function
-        let unallocated_rewards = calculate_unallocated_rewards(settings, stats)?;
-        let available_rewards = if unallocated_rewards > 0 {
-            unallocated_rewards as u64
+        // Same as the accumulators, the pull time never moves back
+        let current_time = Clock::get()?
+            .unix_timestamp
+            .max(reward_stream.last_pull_time);
+        let time_elapsed = (current_time - reward_stream.last_pull_time) as u64;
+
+        // Accrued funding is limited by the cap, the allowance and the treasury balance.
+        // Funding beyond the cap is dropped. What the allowance or the balance can not cover yet is carried over,
+        // by moving the pull time forward only by the whole seconds paid for.
+        let accrued = (reward_stream.rate_per_second as u128)
+            .checked_mul(time_elapsed as u128)
+            .ok_or(StakingError::MathOverflow)?;
+        let cap_left = reward_stream.cap.saturating_sub(reward_stream.total_pulled);
+        let pull_limit = treasury_token_account
+            .delegated_amount
+            .min(treasury_token_account.amount);
+        let capped = accrued.min(cap_left as u128);
+        let (amount, pull_time) = if capped <= pull_limit as u128 {
+            (capped as u64, current_time)
         } else {
-            0 // No rewards available if we've promised more than provided
+            // Only reachable with a non-zero rate, as the accrued funding exceeds the limit
+            let seconds_paid = pull_limit / reward_stream.rate_per_second;
+            (
+                seconds_paid * reward_stream.rate_per_second,
+                reward_stream
+                    .last_pull_time
+                    .checked_add(seconds_paid as i64)
+                    .ok_or(StakingError::MathOverflow)?,
+            )
         };
 
-        // Calculate current reward consumption rate per second
-        let rewards_per_second = (stats.total_staked as u128
-            * settings.reward_rate_per_second_per_token_numerator as u128)
-            / PRECISION as u128;
+        require!(amount > 0, StakingError::InvalidAmount);
 
-        if rewards_per_second == 0 {
-            return Ok(u64::MAX);
-        }
+        update_accumulators(settings, stats)?;
 
-        let runway_seconds = (available_rewards as u128) / rewards_per_second;
-        let runway_seconds = if runway_seconds > u32::MAX as u128 {
-            u64::MAX
-        } else {
-            runway_seconds as u64
-        };
+        reward_stream.last_pull_time = pull_time;
+        reward_stream.total_pulled = reward_stream
+            .total_pulled
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+        stats.total_reward_provided = stats
+            .total_reward_provided
+            .checked_add(amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        let signer: &[&[&[u8]]] = &[&[b"settings", &[ctx.bumps.settings]]];
+        token::transfer(
+            CpiContext::new_with_signer(
+                token_program.to_account_info(),
+                Transfer {
+                    from: treasury_token_account.to_account_info(),
+                    to: protocol_token_account.to_account_info(),
+                    authority: settings.to_account_info(),
+                },
+                signer,
+            ),
+            amount,
+        )?;
 
-        let event = RewardRunwayViewed {
-            available_rewards,
-            runway_seconds,
+        let event = RewardStreamPulled {
+            amount,
+            total_pulled: reward_stream.total_pulled,
+            metadata: next_event_metadata(stats)?,
         };
-        msg!("{:?}", event);
-        emit!(event);
+        emit_cpi!(event);
 
-        Ok(runway_seconds)
+        Ok(())
     }
-}
 
-// ===========================================
-// ============ HELPER FUNCTIONS =============
-// ===========================================
+    // Permissionless, the record only mirrors the stake. Call it in the same transaction as the governance instruction.
+    pub fn update_voter_weight_record(
+        ctx: Context<UpdateVoterWeightRecordAccounts>,
+        realm: Pubkey,
+    ) -> Result<()> {
+        let user = &ctx.accounts.user;
+        let settings = &ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+        let user_info = &ctx.accounts.user_info;
+        let voter_weight_record = &mut ctx.accounts.voter_weight_record;
+
+        let clock = Clock::get()?;
+        let voter_weight = calculate_voter_weight(settings, user_info, clock.unix_timestamp)?;
+
+        voter_weight_record.realm = realm;
+        voter_weight_record.governing_token_mint = settings.token_mint;
+        voter_weight_record.governing_token_owner = user.key();
+        voter_weight_record.voter_weight = voter_weight;
+        // Stake can be withdrawn at any time, so the weight is only valid in the current slot
+        voter_weight_record.voter_weight_expiry = Some(clock.slot);
+        voter_weight_record.weight_action = None;
+        voter_weight_record.weight_action_target = None;
+
+        let event = VoterWeightRecordUpdated {
+            realm,
+            user: user.key(),
+            voter_weight,
+            voter_weight_expiry: clock.slot,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
 
-const PRECISION: u64 = 1_000_000_000_000; // 1e12 scaling factor
-const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
-const SECONDS_PER_YEAR: u64 = 365 * SECONDS_PER_DAY; // 31,536,000 seconds
-const MAX_WITHDRAWAL_DELAY_DAYS: u64 = 31;
+        Ok(())
+    }
+
+    // Permissionless, anyone can move a position to the loyalty tier it has reached
+    pub fn update_loyalty_tier(ctx: Context<UpdateLoyaltyTierAccounts>) -> Result<()> {
+        let user = &ctx.accounts.user;
+        let settings = &ctx.accounts.settings;
+        let stats = &mut ctx.accounts.stats;
+        let user_info = &mut ctx.accounts.user_info;
+
+        capture_rewards(settings, stats, user_info)?;
+
+        let old_loyalty_tier = calculate_position_loyalty_tier(stats, user_info);
+        let stake_amount = user_info.stake_amount;
+        update_position_loyalty_tier(settings, stats, user_info, stake_amount)?;
+
+        let event = LoyaltyTierUpdated {
+            user: user.key(),
+            old_loyalty_tier,
+            new_loyalty_tier: user_info.loyalty_tier,
+            captured_reward: user_info.captured_reward,
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    // Permissionless, anyone can pay for the migration of a user account
+    pub fn migrate_user_info(ctx: Context<MigrateUserInfoAccounts>) -> Result<()> {
+        let payer = &ctx.accounts.payer;
+        let user = &ctx.accounts.user;
+        let user_info = &ctx.accounts.user_info;
+        let stats = &mut ctx.accounts.stats;
+        let user_stake_history = &mut ctx.accounts.user_stake_history;
+        let system_program = &ctx.accounts.system_program;
+
+        let user_info_v1 = match read_account_version::<UserInfo>(user_info, UserInfoV1::SPACE)? {
+            1 => UserInfoV1::deserialize(&mut &user_info.try_borrow_data()?[8..])?,
+            _ => return err!(StakingError::AccountAlreadyMigrated),
+        };
+
+        // Lifetime history is not available on-chain, the tokens still in the position are its lower bound
+        let migrated_user_info = UserInfo {
+            version: UserInfo::VERSION,
+            user: user_info_v1.user,
+            stake_amount: user_info_v1.stake_amount,
+            staked_at: user_info_v1.staked_at as i64,
+            reward_per_token_paid_numerator: scale_v1_reward_numerator(
+                user_info_v1.reward_per_token_paid_numerator,
+            ),
+            captured_reward: user_info_v1.captured_reward,
+            withdrawal_request_time: user_info_v1.withdrawal_request_time as i64,
+            withdrawal_request_amount: user_info_v1.withdrawal_request_amount,
+            withdrawal_request_reward_amount: user_info_v1.withdrawal_request_reward_amount,
+            reward_debt_amount: 0,
+            lifetime_staked: user_info_v1
+                .stake_amount
+                .checked_add(user_info_v1.withdrawal_request_amount)
+                .ok_or(StakingError::MathOverflow)?,
+            lifetime_withdrawn: 0,
+            lifetime_rewards_paid: 0,
+            lifetime_rewards_forfeited: 0,
+            // Joins its loyalty tier with the next capture, see update_loyalty_tier. Until then, it earns no bonus:
+            // the empty schedule of epoch 0 never backdates the bonus of the tiers its age reached.
+            loyalty_tier: None,
+            loyalty_reward_per_token_paid_numerator: 0,
+            loyalty_schedule_epoch: 0,
+            bump: ctx.bumps.user_info,
+        };
+
+        resize_account(user_info, payer, system_program, 8 + UserInfo::INIT_SPACE)?;
+        migrated_user_info.try_serialize(&mut &mut user_info.try_borrow_mut_data()?[..])?;
+
+        // The history starts at the migration, earlier balances are not known on-chain
+        push_stake_checkpoint(
+            user_stake_history,
+            payer,
+            system_program,
+            MAX_USER_STAKE_CHECKPOINTS,
+            migrated_user_info.stake_amount,
+        )?;
+
+        let event = UserInfoMigrated {
+            user: user.key(),
+            payer: payer.key(),
+            metadata: next_event_metadata(stats)?,
+        };
+        emit_cpi!(event);
+
+        Ok(())
+    }
+
+    pub fn view_current_rewards(
+        ctx: Context<ViewCurrentRewardsAccounts>,
+        owner: Pubkey,
+    ) -> Result<u64> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let user_info = &ctx.accounts.user_info;
+
+        // Calculate current reward_per_token_stored_numerator without updating state
+        let current_time = Clock::get()?.unix_timestamp;
+        let current_reward_per_token_stored_numerator =
+            calculate_current_reward_per_token_stored_numerator(settings, stats, current_time)?;
+
+        let uncaptured_reward = calculate_uncaptured_rewards(
+            user_info.stake_amount,
+            current_reward_per_token_stored_numerator,
+            user_info.reward_per_token_paid_numerator,
+        )?
+        .checked_add(calculate_uncaptured_loyalty_reward(
+            settings,
+            stats,
+            user_info,
+            current_time,
+        )?)
+        .ok_or(StakingError::MathOverflow)?;
+        // Rewards locked in a pending withdrawal request are paid out on withdraw,
+        // so they are part of what the user will receive
+        let total_reward = user_info
+            .captured_reward
+            .checked_add(uncaptured_reward)
+            .and_then(|reward| reward.checked_add(user_info.withdrawal_request_reward_amount))
+            .ok_or(StakingError::MathOverflow)?;
+
+        let event = CurrentRewardsViewed {
+            user: owner,
+            captured_reward: user_info.captured_reward,
+            uncaptured_reward,
+            withdrawal_request_reward_amount: user_info.withdrawal_request_reward_amount,
+            total_reward,
+        };
+        emit!(event);
+
+        Ok(total_reward)
+    }
+
+    pub fn view_unallocated_rewards(ctx: Context<ViewUnallocatedRewardsAccounts>) -> Result<i128> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+
+        let unallocated_rewards =
+            calculate_unallocated_rewards(settings, stats, Clock::get()?.unix_timestamp)?;
+
+        let event = UnallocatedRewardsViewed {
+            unallocated_rewards,
+        };
+        emit!(event);
+
+        Ok(unallocated_rewards)
+    }
+
+    pub fn view_reward_runway(ctx: Context<ViewRewardRunwayAccounts>) -> Result<u64> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+
+        let current_time = Clock::get()?.unix_timestamp;
+        let available_rewards = calculate_available_rewards(settings, stats, current_time)?;
+        let runway_seconds = calculate_reward_runway(settings, stats, current_time)?;
+
+        let event = RewardRunwayViewed {
+            available_rewards,
+            runway_seconds,
+        };
+        emit!(event);
+
+        Ok(runway_seconds)
+    }
+
+    pub fn view_pending_withdrawals(ctx: Context<ViewPendingWithdrawalsAccounts>) -> Result<u64> {
+        let _settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+
+        // Staked tokens are returned from the users' own accounts, only the rewards are paid from the protocol account.
+        // Reward debts of partial payouts are claimed from the same account
+        let owed_reward_amount = stats
+            .total_withdrawal_request_reward_amount
+            .checked_add(stats.total_reward_debt)
+            .ok_or(StakingError::MathOverflow)?;
+
+        let event = PendingWithdrawalsViewed {
+            total_withdrawal_request_amount: stats.total_withdrawal_request_amount,
+            total_withdrawal_request_reward_amount: stats.total_withdrawal_request_reward_amount,
+            total_reward_debt: stats.total_reward_debt,
+            protocol_token_account_amount: protocol_token_account.amount,
+        };
+        emit!(event);
+
+        Ok(owed_reward_amount)
+    }
+
+    // Anyone can simulate it for any owner
+    pub fn view_position(
+        ctx: Context<ViewPositionAccounts>,
+        owner: Pubkey,
+    ) -> Result<PositionView> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let user_info = &ctx.accounts.user_info;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+
+        let current_time = Clock::get()?.unix_timestamp;
+        let current_reward_per_token_stored_numerator =
+            calculate_current_reward_per_token_stored_numerator(settings, stats, current_time)?;
+        let uncaptured_reward = calculate_uncaptured_rewards(
+            user_info.stake_amount,
+            current_reward_per_token_stored_numerator,
+            user_info.reward_per_token_paid_numerator,
+        )?
+        .checked_add(calculate_uncaptured_loyalty_reward(
+            settings,
+            stats,
+            user_info,
+            current_time,
+        )?)
+        .ok_or(StakingError::MathOverflow)?;
+
+        let has_withdrawal_request = user_info.withdrawal_request_amount > 0
+            || user_info.withdrawal_request_reward_amount > 0;
+        let withdrawal_unlock_time = if has_withdrawal_request {
+            Some(
+                user_info
+                    .withdrawal_request_time
+                    .checked_add(settings.withdrawal_delay_seconds as i64)
+                    .ok_or(StakingError::MathOverflow)?,
+            )
+        } else {
+            None
+        };
+
+        // Same checks as in withdraw
+        let can_withdraw = withdrawal_unlock_time
+            .is_some_and(|withdrawal_unlock_time| current_time >= withdrawal_unlock_time)
+            && (protocol_token_account.amount >= user_info.withdrawal_request_reward_amount
+                || settings.partial_reward_payout_enabled);
+
+        // Positions assessed under the current schedule earn the bonus of the last tier reached,
+        // the others none until their next capture
+        let reached_loyalty_tier = calculate_loyalty_tier(settings, user_info, current_time);
+        let earning_loyalty_tier =
+            if user_info.loyalty_schedule_epoch == stats.loyalty_schedule_epoch {
+                reached_loyalty_tier
+            } else {
+                None
+            };
+
+        let position = PositionView {
+            owner,
+            stake_amount: user_info.stake_amount,
+            staked_at: user_info.staked_at,
+            captured_reward: user_info.captured_reward,
+            uncaptured_reward,
+            withdrawal_request_amount: user_info.withdrawal_request_amount,
+            withdrawal_request_reward_amount: user_info.withdrawal_request_reward_amount,
+            reward_debt_amount: user_info.reward_debt_amount,
+            withdrawal_unlock_time,
+            can_withdraw,
+            loyalty_tier: calculate_position_loyalty_tier(stats, user_info),
+            reached_loyalty_tier,
+            effective_reward_rate_yearly_percentage_numerator:
+                calculate_effective_reward_rate_yearly_percentage_numerator(
+                    settings,
+                    stats,
+                    earning_loyalty_tier,
+                    current_time,
+                )?,
+        };
+
+        let event = PositionViewed {
+            position: position.clone(),
+        };
+        emit!(event);
+
+        Ok(position)
+    }
+
+    pub fn view_pool(ctx: Context<ViewPoolAccounts>) -> Result<PoolView> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+
+        let current_time = Clock::get()?.unix_timestamp;
+
+        let pool = PoolView {
+            reward_rate_yearly_percentage_numerator:
+                calculate_reward_rate_yearly_percentage_numerator(
+                    settings.reward_rate_per_second_per_token_numerator,
+                )?,
+            reward_rate_per_second_per_token_numerator: settings
+                .reward_rate_per_second_per_token_numerator,
+            total_staked: stats.total_staked,
+            total_reward_promised: calculate_total_promised_rewards(settings, stats, current_time)?,
+            total_reward_provided: stats.total_reward_provided,
+            protocol_token_account_amount: protocol_token_account.amount,
+            unallocated_rewards: calculate_unallocated_rewards(settings, stats, current_time)?,
+            runway_seconds: calculate_reward_runway(settings, stats, current_time)?,
+            withdrawal_delay_seconds: settings.withdrawal_delay_seconds,
+            rewards_end_time: settings.rewards_end_time,
+            accrual_paused: is_accrual_paused(settings, stats, current_time)?,
+            solvency_mode_enabled: settings.solvency_mode_enabled,
+            partial_reward_payout_enabled: settings.partial_reward_payout_enabled,
+        };
+
+        let event = PoolViewed { pool: pool.clone() };
+        emit!(event);
+
+        Ok(pool)
+    }
+
+    // Projection assumes nothing changes until the timestamp: no stakes, withdrawals, funding or configuration changes
+    pub fn view_projected_rewards(
+        ctx: Context<ViewProjectedRewardsAccounts>,
+        owner: Pubkey,
+        timestamp: i64,
+    ) -> Result<ProjectedRewardsView> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let user_info = &ctx.accounts.user_info;
+
+        require!(
+            timestamp >= Clock::get()?.unix_timestamp,
+            StakingError::InvalidTimestamp
+        );
+
+        let projected_reward_per_token_stored_numerator =
+            calculate_current_reward_per_token_stored_numerator(settings, stats, timestamp)?;
+        let uncaptured_reward = calculate_uncaptured_rewards(
+            user_info.stake_amount,
+            projected_reward_per_token_stored_numerator,
+            user_info.reward_per_token_paid_numerator,
+        )?
+        .checked_add(calculate_uncaptured_loyalty_reward(
+            settings, stats, user_info, timestamp,
+        )?)
+        .ok_or(StakingError::MathOverflow)?;
+
+        let projection = ProjectedRewardsView {
+            owner,
+            timestamp,
+            captured_reward: user_info.captured_reward,
+            uncaptured_reward,
+            withdrawal_request_reward_amount: user_info.withdrawal_request_reward_amount,
+            // Same total as view_current_rewards, rewards of a pending withdrawal request included
+            total_reward: user_info
+                .captured_reward
+                .checked_add(uncaptured_reward)
+                .and_then(|reward| reward.checked_add(user_info.withdrawal_request_reward_amount))
+                .ok_or(StakingError::MathOverflow)?,
+            unallocated_rewards: calculate_unallocated_rewards(settings, stats, timestamp)?,
+        };
+
+        let event = ProjectedRewardsViewed {
+            projection: projection.clone(),
+        };
+        emit!(event);
+
+        Ok(projection)
+    }
+
+    pub fn view_solvency(ctx: Context<ViewSolvencyAccounts>) -> Result<SolvencyView> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+        let protocol_token_account = &ctx.accounts.protocol_token_account;
+
+        // Staked and queued tokens are kept in the users' own accounts, so they are backed one to one
+        let user_vault_amount = stats
+            .total_staked
+            .checked_add(stats.total_withdrawal_request_amount)
+            .ok_or(StakingError::MathOverflow)?;
+
+        // Only the exact accounting fields are used, the lifetime counters are statistics.
+        // Rewards provided, but not promised, are the only tokens of the protocol account owed to no one,
+        // the rest of the balance is owed to the stakers, the withdrawal queue and the reward debts.
+        // The queued rewards and the reward debts are owed in full, even if the balance runs short.
+        let unallocated_rewards =
+            calculate_unallocated_rewards(settings, stats, Clock::get()?.unix_timestamp)?;
+        let outstanding_reward_amount = stats
+            .total_withdrawal_request_reward_amount
+            .checked_add(stats.total_reward_debt)
+            .ok_or(StakingError::MathOverflow)?;
+        let reward_liability = u64::try_from(
+            (protocol_token_account.amount as i128 - unallocated_rewards)
+                .max(outstanding_reward_amount as i128),
+        )
+        .map_err(|_| StakingError::MathOverflow)?;
+
+        let collateralization_ratio_numerator = if reward_liability == 0 {
+            u64::MAX
+        } else {
+            mul_div(
+                protocol_token_account.amount,
+                PRECISION as u128,
+                reward_liability as u128,
+            )
+            .and_then(|ratio| u64::try_from(ratio).ok())
+            .unwrap_or(u64::MAX)
+        };
+
+        let solvency = SolvencyView {
+            protocol_token_account_amount: protocol_token_account.amount,
+            total_staked: stats.total_staked,
+            total_withdrawal_request_amount: stats.total_withdrawal_request_amount,
+            user_vault_amount,
+            total_withdrawal_request_reward_amount: stats.total_withdrawal_request_reward_amount,
+            total_reward_debt: stats.total_reward_debt,
+            reward_liability,
+            reward_surplus: protocol_token_account.amount as i128 - reward_liability as i128,
+            collateralization_ratio_numerator,
+        };
+
+        let event = SolvencyViewed {
+            solvency: solvency.clone(),
+        };
+        emit!(event);
+
+        Ok(solvency)
+    }
+
+    pub fn view_positions<'info>(
+        ctx: Context<'_, '_, 'info, 'info, ViewPositionsAccounts<'info>>,
+    ) -> Result<PositionsView> {
+        let settings = &ctx.accounts.settings;
+        let stats = &ctx.accounts.stats;
+
+        // All positions are computed against the same accumulator value
+        let current_time = Clock::get()?.unix_timestamp;
+        let current_reward_per_token_stored_numerator =
+            calculate_current_reward_per_token_stored_numerator(settings, stats, current_time)?;
+
+        let mut positions = Vec::with_capacity(ctx.remaining_accounts.len());
+        for account in ctx.remaining_accounts {
+            // Checks the owner and the discriminator
+            let user_info = Account::<UserInfo>::try_from(account)?;
+            let expected_address = Pubkey::create_program_address(
+                &[b"user_info", user_info.user.as_ref(), &[user_info.bump]],
+                &crate::ID,
+            )
+            .map_err(|_| StakingError::InvalidUserInfo)?;
+            require_keys_eq!(
+                account.key(),
+                expected_address,
+                StakingError::InvalidUserInfo
+            );
+
+            let uncaptured_reward = calculate_uncaptured_rewards(
+                user_info.stake_amount,
+                current_reward_per_token_stored_numerator,
+                user_info.reward_per_token_paid_numerator,
+            )?
+            .checked_add(calculate_uncaptured_loyalty_reward(
+                settings,
+                stats,
+                &user_info,
+                current_time,
+            )?)
+            .ok_or(StakingError::MathOverflow)?;
+            let total_reward = user_info
+                .captured_reward
+                .checked_add(uncaptured_reward)
+                .and_then(|reward| reward.checked_add(user_info.withdrawal_request_reward_amount))
+                .ok_or(StakingError::MathOverflow)?;
+
+            positions.push(PositionRewardsView {
+                owner: user_info.user,
+                stake_amount: user_info.stake_amount,
+                captured_reward: user_info.captured_reward,
+                uncaptured_reward,
+                withdrawal_request_reward_amount: user_info.withdrawal_request_reward_amount,
+                total_reward,
+            });
+        }
+
+        let positions = PositionsView {
+            timestamp: current_time,
+            reward_per_token_stored_numerator: current_reward_per_token_stored_numerator,
+            positions,
+        };
+
+        let event = PositionsViewed {
+            positions: positions.clone(),
+        };
+        emit!(event);
+
+        Ok(positions)
+    }
+
+    pub fn view_reward_rate_at(
+        ctx: Context<ViewRewardRateAtAccounts>,
+        timestamp: i64,
+    ) -> Result<RewardRateView> {
+        let settings = &ctx.accounts.settings;
+        let rate_history = &ctx.accounts.rate_history;
+
+        // Checkpoints are ordered, as the accumulators update time never moves back
+        let checkpoints = &rate_history.checkpoints;
+        let index = checkpoints.partition_point(|checkpoint| checkpoint.timestamp <= timestamp);
+        let (reward_rate_per_second_per_token_numerator, effective_since) = match index {
+            // Before the first checkpoint the pool ran under the initial or the migrated rate, since an unknown time
+            0 => (
+                checkpoints.first().map_or(
+                    settings.reward_rate_per_second_per_token_numerator,
+                    |checkpoint| checkpoint.old_reward_rate_per_second_per_token_numerator,
+                ),
+                None,
+            ),
+            _ => (
+                checkpoints[index - 1].new_reward_rate_per_second_per_token_numerator,
+                Some(checkpoints[index - 1].timestamp),
+            ),
+        };
+
+        let rate = RewardRateView {
+            timestamp,
+            reward_rate_yearly_percentage_numerator:
+                calculate_reward_rate_yearly_percentage_numerator(
+                    reward_rate_per_second_per_token_numerator,
+                )?,
+            reward_rate_per_second_per_token_numerator,
+            effective_since,
+        };
+
+        let event = RewardRateViewed { rate: rate.clone() };
+        emit!(event);
+
+        Ok(rate)
+    }
+
+    pub fn view_staked_at_time(
+        ctx: Context<ViewStakedAtTimeAccounts>,
+        owner: Pubkey,
+        timestamp: i64,
+    ) -> Result<u64> {
+        let stake_history = &ctx.accounts.stake_history;
+
+        let amount = find_staked_amount_at_time(stake_history, timestamp)?;
+
+        let event = StakedAtTimeViewed {
+            owner,
+            timestamp,
+            amount,
+        };
+        emit!(event);
+
+        Ok(amount)
+    }
+
+    pub fn view_total_staked_at_time(
+        ctx: Context<ViewTotalStakedAtTimeAccounts>,
+        timestamp: i64,
+    ) -> Result<u64> {
+        let total_stake_history = &ctx.accounts.total_stake_history;
+
+        let amount = find_staked_amount_at_time(total_stake_history, timestamp)?;
+
+        let event = TotalStakedAtTimeViewed { timestamp, amount };
+        emit!(event);
+
+        Ok(amount)
+    }
+}
+
+// ===========================================
+// ============ HELPER FUNCTIONS =============
+// ===========================================
+
+const PRECISION: u64 = 1_000_000_000_000; // 1e12 scaling factor of the yearly percentage
+const REWARD_PRECISION: u128 = 1_000_000_000_000_000_000_000_000_000; // 1e27 scaling factor of the reward rate and accumulators
+const V1_REWARD_PRECISION: u128 = PRECISION as u128; // Scaling factor of the accumulators in v1.0.0
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+const SECONDS_PER_YEAR: u64 = 365 * SECONDS_PER_DAY; // 31,536,000 seconds
+const MAX_WITHDRAWAL_DELAY_DAYS: u64 = 31;
+const MAX_LOYALTY_TIERS: usize = 8;
+const MAX_USER_STAKE_CHECKPOINTS: usize = 4096; // Ring size of a user's stake history, about 96 KiB
+                                                // Ring size of the pool's stake history, about 9 MiB, below the 10 MiB account size limit.
+                                                // Snapshot lookups only have to reach back to the snapshot of the oldest vote still open,
+                                                // and every stake and withdrawal request of the pool records a checkpoint: at a thousand changes a day,
+                                                // the ring keeps more than a year.
+const MAX_TOTAL_STAKE_CHECKPOINTS: usize = 400_000;
 
 fn update_accumulators(settings: &Settings, stats: &mut Stats) -> Result<()> {
-    let current_time = Clock::get()?.unix_timestamp as u32;
+    // Never move the update time back, so that a clock going backwards can not pay the same period twice
+    let current_time = Clock::get()?.unix_timestamp.max(stats.last_update_time);
+    update_accumulators_to(settings, stats, current_time)
+}
+
+fn update_accumulators_to(settings: &Settings, stats: &mut Stats, current_time: i64) -> Result<()> {
     if stats.total_staked == 0 {
         stats.last_update_time = current_time;
         return Ok(());
     }
 
-    let time_elapsed = current_time - stats.last_update_time;
+    let (reward_increment_numerator, loyalty_reward_increment_numerators) =
+        calculate_reward_increment_numerators(settings, stats, current_time)?;
+
+    // Total rewards promised = staked tokens * rewards_per_token_increment / PRECISION, loyalty tiers included
+    let total_new_reward = calculate_promised_reward_increment(
+        stats,
+        reward_increment_numerator,
+        &loyalty_reward_increment_numerators,
+    )?;
+
+    stats.reward_per_token_stored_numerator = stats
+        .reward_per_token_stored_numerator
+        .checked_add(reward_increment_numerator)
+        .ok_or(StakingError::MathOverflow)?;
+    for (loyalty_reward_per_token_stored_numerator, loyalty_reward_increment_numerator) in stats
+        .loyalty_reward_per_token_stored_numerator
+        .iter_mut()
+        .zip(loyalty_reward_increment_numerators)
+    {
+        *loyalty_reward_per_token_stored_numerator = loyalty_reward_per_token_stored_numerator
+            .checked_add(loyalty_reward_increment_numerator)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+    stats.total_reward_promised = stats
+        .total_reward_promised
+        .checked_add(total_new_reward)
+        .ok_or(StakingError::MathOverflow)?;
+
+    stats.last_update_time = current_time;
 
-    if time_elapsed > 0 {
-        // Simple: time_elapsed * rate_per_token_numerator = rewards_per_token_numerator to add
-        let reward_increment_numerator =
-            settings.reward_rate_per_second_per_token_numerator * time_elapsed as u64;
+    Ok(())
+}
 
-        stats.reward_per_token_stored_numerator += reward_increment_numerator;
+fn calculate_accrual_time_elapsed(
+    settings: &Settings,
+    last_update_time: i64,
+    current_time: i64,
+) -> u64 {
+    let accrual_time = match settings.rewards_end_time {
+        Some(rewards_end_time) => current_time.min(rewards_end_time),
+        None => current_time,
+    };
 
-        // Total rewards promised = total_staked * rewards_per_token_increment / PRECISION
-        let total_new_reward = ((stats.total_staked as u128)
-            * (reward_increment_numerator as u128))
-            / (PRECISION as u128);
-        stats.total_reward_promised += total_new_reward as u64;
+    accrual_time.saturating_sub(last_update_time).max(0) as u64
+}
 
-        stats.last_update_time = current_time;
+// Accumulator growth since the last update, not stored in stats yet: of the base rewards, and of the bonus of
+// every loyalty tier. In solvency mode the growth is capped, so that promised rewards never exceed provided rewards.
+// The base rewards are capped first, then the loyalty tiers in their order.
+fn calculate_reward_increment_numerators(
+    settings: &Settings,
+    stats: &Stats,
+    current_time: i64,
+) -> Result<(u128, [u128; MAX_LOYALTY_TIERS])> {
+    let time_elapsed =
+        calculate_accrual_time_elapsed(settings, stats.last_update_time, current_time) as u128;
+
+    let mut rewards_left = if settings.solvency_mode_enabled {
+        Some(
+            stats
+                .total_reward_provided
+                .checked_add(stats.total_reward_forfeited)
+                .ok_or(StakingError::MathOverflow)?
+                .saturating_sub(stats.total_reward_promised),
+        )
+    } else {
+        None
+    };
+
+    // Simple: time_elapsed * rate_per_token_numerator = rewards_per_token_numerator to add
+    let reward_increment_numerator = cap_reward_increment_numerator(
+        stats.total_staked,
+        settings
+            .reward_rate_per_second_per_token_numerator
+            .checked_mul(time_elapsed)
+            .ok_or(StakingError::MathOverflow)?,
+        &mut rewards_left,
+    )?;
+
+    let mut loyalty_reward_increment_numerators = [0; MAX_LOYALTY_TIERS];
+    for (index, tier) in settings.loyalty_tiers.iter().enumerate() {
+        loyalty_reward_increment_numerators[index] = cap_reward_increment_numerator(
+            stats.loyalty_tier_staked[index],
+            tier.bonus_rate_per_second_per_token_numerator
+                .checked_mul(time_elapsed)
+                .ok_or(StakingError::MathOverflow)?,
+            &mut rewards_left,
+        )?;
     }
 
-    Ok(())
+    Ok((
+        reward_increment_numerator,
+        loyalty_reward_increment_numerators,
+    ))
 }
 
-fn capture_rewards(settings: &Settings, stats: &mut Stats, user_info: &mut UserInfo) -> Result<()> {
-    // Update accumulators first to get current state
-    update_accumulators(settings, stats)?;
-
-    user_info.captured_reward += calculate_uncaptured_rewards(
-        user_info.stake_amount,
-        stats.reward_per_token_stored_numerator,
-        user_info.reward_per_token_paid_numerator,
-    );
+// Caps the growth of an accumulator by the rewards left, if they are limited, and takes what it promises from them
+fn cap_reward_increment_numerator(
+    staked: u64,
+    reward_increment_numerator: u128,
+    rewards_left: &mut Option<u64>,
+) -> Result<u128> {
+    if staked == 0 {
+        return Ok(0);
+    }
+    let Some(rewards_left) = rewards_left else {
+        return Ok(reward_increment_numerator);
+    };
+
+    let reward_increment_numerator = match mul_div(*rewards_left, REWARD_PRECISION, staked as u128)
+    {
+        Some(max_reward_increment_numerator) => {
+            // Rounded up, so that the capped growth promises exactly the rewards left. Rounding down would leave
+            // a fraction of a token, that keeps the accumulator growing without ever being promised.
+            let max_reward_increment_numerator =
+                if calculate_reward_amount(staked, max_reward_increment_numerator)? < *rewards_left
+                {
+                    max_reward_increment_numerator + 1
+                } else {
+                    max_reward_increment_numerator
+                };
+            reward_increment_numerator.min(max_reward_increment_numerator)
+        }
+        // A cap too large for u128 can not be reached
+        None => reward_increment_numerator,
+    };
 
-    // Reset user's snapshot to current accumulator value
-    user_info.reward_per_token_paid_numerator = stats.reward_per_token_stored_numerator;
+    *rewards_left = rewards_left
+        .checked_sub(calculate_reward_amount(staked, reward_increment_numerator)?)
+        .ok_or(StakingError::MathOverflow)?;
 
-    Ok(())
+    Ok(reward_increment_numerator)
 }
 
-fn calculate_uncaptured_rewards(
-    stake_amount: u64,
-    reward_per_token_stored_numerator: u64,
-    reward_per_token_paid_numerator: u64,
-) -> u64 {
-    let reward_per_token_diff = reward_per_token_stored_numerator - reward_per_token_paid_numerator;
+// Rewards promised by the growth of the accumulators, to the staked tokens and to the tokens in every loyalty tier
+fn calculate_promised_reward_increment(
+    stats: &Stats,
+    reward_increment_numerator: u128,
+    loyalty_reward_increment_numerators: &[u128; MAX_LOYALTY_TIERS],
+) -> Result<u64> {
+    let mut promised_reward_increment =
+        calculate_reward_amount(stats.total_staked, reward_increment_numerator)?;
+    for (tier_staked, loyalty_reward_increment_numerator) in stats
+        .loyalty_tier_staked
+        .iter()
+        .zip(loyalty_reward_increment_numerators)
+    {
+        promised_reward_increment = promised_reward_increment
+            .checked_add(calculate_reward_amount(
+                *tier_staked,
+                *loyalty_reward_increment_numerator,
+            )?)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+
+    Ok(promised_reward_increment)
+}
 
-    let earned = ((stake_amount as u128) * (reward_per_token_diff as u128)) / (PRECISION as u128);
+fn calculate_reward_amount(amount: u64, reward_per_token_numerator: u128) -> Result<u64> {
+    let reward = mul_div(amount, reward_per_token_numerator, REWARD_PRECISION)
+        .ok_or(StakingError::MathOverflow)?;
 
-    earned as u64
+    Ok(u64::try_from(reward).map_err(|_| StakingError::MathOverflow)?)
 }
 
-fn calculate_total_promised_rewards(settings: &Settings, stats: &Stats) -> Result<u64> {
-    let time_elapsed = Clock::get()?.unix_timestamp as u32 - stats.last_update_time;
+// Per-second rate, scaled by REWARD_PRECISION. The truncation loses less than a base unit a year, even on u64::MAX staked tokens.
+fn calculate_reward_rate_per_second_per_token_numerator(
+    reward_rate_yearly_percentage_numerator: u64,
+) -> u128 {
+    reward_rate_yearly_percentage_numerator as u128 * (REWARD_PRECISION / PRECISION as u128)
+        / SECONDS_PER_YEAR as u128
+}
 
-    Ok(stats.total_reward_promised
-        + (settings.reward_rate_per_second_per_token_numerator as u128
-            * time_elapsed as u128
-            * stats.total_staked as u128
-            / PRECISION as u128) as u64)
+fn scale_v1_reward_numerator(reward_numerator: u64) -> u128 {
+    reward_numerator as u128 * (REWARD_PRECISION / V1_REWARD_PRECISION)
 }
 
-fn calculate_unallocated_rewards(settings: &Settings, stats: &Stats) -> Result<i128> {
-    let total_promised = calculate_total_promised_rewards(settings, stats)?;
-    Ok(stats.total_reward_provided as i128 - total_promised as i128)
+// floor(a * b / c) without a 256 bit intermediate. Returns None if the result does not fit into u128.
+// Requires c < 2^95, so that none of the partial products can overflow.
+fn mul_div(a: u64, b: u128, c: u128) -> Option<u128> {
+    // a * b / c = a * q + a * r / c
+    let (q, r) = (b / c, b % c);
+
+    // a * r / c, with a = a_high * 2^32 + a_low
+    let (a_high, a_low) = ((a >> 32) as u128, (a & u32::MAX as u64) as u128);
+    let (q_high, r_high) = (a_high * r / c, a_high * r % c);
+    let q_low = ((r_high << 32) + a_low * r) / c;
+
+    (a as u128)
+        .checked_mul(q)?
+        .checked_add(q_high << 32)?
+        .checked_add(q_low)
 }
 
-// ===========================================
-// ============ ACCOUNT STRUCTURES ===========
-// ===========================================
+fn calculate_seconds_from_days(days: u64) -> Result<u32> {
+    let seconds = days
+        .checked_mul(SECONDS_PER_DAY)
+        .ok_or(StakingError::MathOverflow)?;
 
-#[account]
-#[derive(InitSpace)]
-pub struct Settings {
-    pub administrator: Pubkey,
-    pub pending_administrator: Option<Pubkey>,
-    pub token_mint: Pubkey,
-    pub withdrawal_delay_seconds: u32,
-    pub reward_rate_per_second_per_token_numerator: u64,
+    Ok(u32::try_from(seconds).map_err(|_| StakingError::MathOverflow)?)
 }
 
-#[account]
-#[derive(InitSpace)]
-pub struct Stats {
-    pub reward_per_token_stored_numerator: u64,
-    pub last_update_time: u32,
-    pub total_staked: u64,
-    pub total_reward_promised: u64,
-    pub total_reward_provided: u64,
+// Stake, plus the age bonus growing linearly with the time since the position was opened
+// Start of a position extended by the given amount, so that its age is the amount-weighted age of its tokens.
+// Rounded towards the current time, the added tokens never gain age.
+fn calculate_weighted_staked_at(
+    user_info: &UserInfo,
+    amount: u64,
+    current_time: i64,
+) -> Result<i64> {
+    let age = current_time.saturating_sub(user_info.staked_at).max(0) as u128;
+    let total_amount = user_info
+        .stake_amount
+        .checked_add(amount)
+        .ok_or(StakingError::MathOverflow)?;
+    let weighted_age = mul_div(user_info.stake_amount, age, total_amount as u128)
+        .and_then(|weighted_age| i64::try_from(weighted_age).ok())
+        .ok_or(StakingError::MathOverflow)?;
+
+    Ok(current_time.saturating_sub(weighted_age))
 }
 
-#[account]
+fn calculate_voter_weight(
+    settings: &Settings,
+    user_info: &UserInfo,
+    current_time: i64,
+) -> Result<u64> {
+    if user_info.stake_amount == 0 {
+        return Ok(0);
+    }
+
+    let age = current_time.saturating_sub(user_info.staked_at).max(0) as u128;
+    let saturation = settings.voter_weight_age_bonus_saturation_seconds as u128;
+    let age_bonus_numerator = if age >= saturation {
+        settings.voter_weight_age_bonus_numerator as u128
+    } else {
+        settings.voter_weight_age_bonus_numerator as u128 * age / saturation
+    };
+    let age_bonus = mul_div(
+        user_info.stake_amount,
+        age_bonus_numerator,
+        PRECISION as u128,
+    )
+    .and_then(|age_bonus| u64::try_from(age_bonus).ok())
+    .ok_or(StakingError::MathOverflow)?;
+
+    Ok(user_info
+        .stake_amount
+        .checked_add(age_bonus)
+        .ok_or(StakingError::MathOverflow)?)
+}
+
+fn capture_rewards(settings: &Settings, stats: &mut Stats, user_info: &mut UserInfo) -> Result<()> {
+    // Update accumulators first to get current state
+    update_accumulators(settings, stats)?;
+    capture_accrued_rewards(settings, stats, user_info)
+}
+
+// Moves the rewards accrued since the last capture into the captured rewards.
+// The accumulators promised them already, except for the bonus of the loyalty tiers reached since.
+fn capture_accrued_rewards(
+    settings: &Settings,
+    stats: &mut Stats,
+    user_info: &mut UserInfo,
+) -> Result<()> {
+    let uncaptured_reward = calculate_uncaptured_rewards(
+        user_info.stake_amount,
+        stats.reward_per_token_stored_numerator,
+        user_info.reward_per_token_paid_numerator,
+    )?;
+    user_info.captured_reward = user_info
+        .captured_reward
+        .checked_add(uncaptured_reward)
+        .ok_or(StakingError::MathOverflow)?;
+
+    // Reset user's snapshot to current accumulator value
+    user_info.reward_per_token_paid_numerator = stats.reward_per_token_stored_numerator;
+
+    let (loyalty_reward, unpromised_loyalty_reward, forfeited_loyalty_reward) =
+        calculate_accrued_loyalty_reward(
+            settings,
+            stats,
+            user_info,
+            &stats.loyalty_reward_per_token_stored_numerator,
+            stats.last_update_time,
+        )?;
+    user_info.captured_reward = user_info
+        .captured_reward
+        .checked_add(loyalty_reward)
+        .ok_or(StakingError::MathOverflow)?;
+    stats.total_reward_promised = stats
+        .total_reward_promised
+        .checked_add(unpromised_loyalty_reward)
+        .ok_or(StakingError::MathOverflow)?;
+    // Promised by the accumulator of the old tier, but a higher tier paying less took over
+    stats.total_reward_forfeited = stats
+        .total_reward_forfeited
+        .checked_add(forfeited_loyalty_reward)
+        .ok_or(StakingError::MathOverflow)?;
+    if let Some(tier) = calculate_position_loyalty_tier(stats, user_info) {
+        user_info.loyalty_reward_per_token_paid_numerator =
+            stats.loyalty_reward_per_token_stored_numerator[tier as usize];
+    }
+
+    Ok(())
+}
+
+// Replaces the loyalty schedule, once the accumulators are settled under the old one, and returns the old tiers.
+// Memberships of the old schedule no longer count. Positions earn no bonus, until their next capture,
+// or update_loyalty_tier, assesses them under the new schedule. The capture still pays them the bonus of
+// the old schedule until the change.
+fn replace_loyalty_schedule(
+    settings: &mut Settings,
+    stats: &mut Stats,
+    new_loyalty_tiers: Vec<LoyaltyTier>,
+) -> Result<Vec<LoyaltyTier>> {
+    let old_loyalty_tiers = std::mem::replace(&mut settings.loyalty_tiers, new_loyalty_tiers);
+    settings.previous_loyalty_tiers = old_loyalty_tiers.clone();
+
+    stats.previous_loyalty_reward_per_token_stored_numerator =
+        stats.loyalty_reward_per_token_stored_numerator;
+    stats.loyalty_schedule_start_time = stats.last_update_time;
+    stats.loyalty_tier_staked = [0; MAX_LOYALTY_TIERS];
+    stats.loyalty_schedule_epoch = stats
+        .loyalty_schedule_epoch
+        .checked_add(1)
+        .ok_or(StakingError::MathOverflow)?;
+
+    Ok(old_loyalty_tiers)
+}
+
+// Loyalty bonus accrued since the last capture, given the accumulators of the tiers at current_time.
+// The accumulator of the tier the position joined pays its bonus until current_time. Tiers its age reached since
+// pay from the moment it reached them instead, so the difference to the joined tier is added, or taken off.
+// Returns the bonus, the part of it not promised yet, and the part promised, but not earned.
+fn calculate_accrued_loyalty_reward(
+    settings: &Settings,
+    stats: &Stats,
+    user_info: &UserInfo,
+    loyalty_reward_per_token_stored_numerators: &[u128; MAX_LOYALTY_TIERS],
+    current_time: i64,
+) -> Result<(u64, u64, u64)> {
+    // Positions of the previous schedule earn its bonus until the change, older ones nothing
+    let (loyalty_tiers, loyalty_reward_per_token_stored_numerators, accrual_time) =
+        if user_info.loyalty_schedule_epoch == stats.loyalty_schedule_epoch {
+            (
+                &settings.loyalty_tiers,
+                loyalty_reward_per_token_stored_numerators,
+                current_time,
+            )
+        } else if user_info.loyalty_schedule_epoch.checked_add(1)
+            == Some(stats.loyalty_schedule_epoch)
+        {
+            (
+                &settings.previous_loyalty_tiers,
+                &stats.previous_loyalty_reward_per_token_stored_numerator,
+                stats.loyalty_schedule_start_time,
+            )
+        } else {
+            return Ok((0, 0, 0));
+        };
+
+    let promised_reward = match user_info.loyalty_tier {
+        Some(tier) => calculate_uncaptured_rewards(
+            user_info.stake_amount,
+            loyalty_reward_per_token_stored_numerators[tier as usize],
+            user_info.loyalty_reward_per_token_paid_numerator,
+        )?,
+        None => 0,
+    };
+
+    let (mut gained_reward, lost_reward) =
+        calculate_loyalty_tier_change_rewards(settings, loyalty_tiers, user_info, accrual_time)?;
+    // In solvency mode, the bonus not promised yet is capped like the accumulators
+    if settings.solvency_mode_enabled {
+        gained_reward =
+            gained_reward.min(calculate_available_rewards(settings, stats, current_time)?);
+    }
+
+    let earned_reward = promised_reward
+        .checked_add(gained_reward)
+        .ok_or(StakingError::MathOverflow)?;
+    let reward = earned_reward.saturating_sub(lost_reward);
+
+    Ok((reward, gained_reward, earned_reward - reward))
+}
+
+// Bonus of the tiers reached after the one the position joined, from the moment the age reached each of them until
+// current_time, minus the bonus of the tier below it for the same time. The gains and the losses are returned apart.
+fn calculate_loyalty_tier_change_rewards(
+    settings: &Settings,
+    loyalty_tiers: &[LoyaltyTier],
+    user_info: &UserInfo,
+    current_time: i64,
+) -> Result<(u64, u64)> {
+    let accrual_time = match settings.rewards_end_time {
+        Some(rewards_end_time) => current_time.min(rewards_end_time),
+        None => current_time,
+    };
+    // The tiers above the joined one were not reached when it was joined
+    let first_tier = user_info.loyalty_tier.map_or(0, |tier| tier as usize + 1);
+
+    let mut gained_reward_numerator: u128 = 0;
+    let mut lost_reward_numerator: u128 = 0;
+    for (index, tier) in loyalty_tiers.iter().enumerate().skip(first_tier) {
+        let reached_time = user_info
+            .staked_at
+            .checked_add(tier.min_staked_seconds as i64)
+            .ok_or(StakingError::MathOverflow)?;
+        if reached_time >= accrual_time {
+            break;
+        }
+
+        let time_in_tier = (accrual_time - reached_time) as u128;
+        let previous_bonus_rate_per_second_per_token_numerator =
+            index.checked_sub(1).map_or(0, |previous| {
+                loyalty_tiers[previous].bonus_rate_per_second_per_token_numerator
+            });
+        let bonus_rate = tier.bonus_rate_per_second_per_token_numerator;
+        if bonus_rate >= previous_bonus_rate_per_second_per_token_numerator {
+            gained_reward_numerator = (bonus_rate
+                - previous_bonus_rate_per_second_per_token_numerator)
+                .checked_mul(time_in_tier)
+                .and_then(|numerator| gained_reward_numerator.checked_add(numerator))
+                .ok_or(StakingError::MathOverflow)?;
+        } else {
+            lost_reward_numerator = (previous_bonus_rate_per_second_per_token_numerator
+                - bonus_rate)
+                .checked_mul(time_in_tier)
+                .and_then(|numerator| lost_reward_numerator.checked_add(numerator))
+                .ok_or(StakingError::MathOverflow)?;
+        }
+    }
+
+    Ok((
+        calculate_reward_amount(user_info.stake_amount, gained_reward_numerator)?,
+        calculate_reward_amount(user_info.stake_amount, lost_reward_numerator)?,
+    ))
+}
+
+// Tier the position earns the bonus of through the accumulators. None, if it joined it under an older schedule.
+fn calculate_position_loyalty_tier(stats: &Stats, user_info: &UserInfo) -> Option<u8> {
+    if user_info.loyalty_schedule_epoch == stats.loyalty_schedule_epoch {
+        user_info.loyalty_tier
+    } else {
+        None
+    }
+}
+
+// Last loyalty tier reached by the age of the position. None without a stake, or before the first tier.
+fn calculate_loyalty_tier(
+    settings: &Settings,
+    user_info: &UserInfo,
+    current_time: i64,
+) -> Option<u8> {
+    if user_info.stake_amount == 0 {
+        return None;
+    }
+
+    let age = current_time.saturating_sub(user_info.staked_at);
+    settings
+        .loyalty_tiers
+        .iter()
+        .rposition(|tier| age >= tier.min_staked_seconds as i64)
+        .map(|index| index as u8)
+}
+
+// Moves a position to the loyalty tier its age has reached, under the current schedule. Call after capturing its
+// rewards, with the stake amount it had then, as that is the amount its tier holds.
+fn update_position_loyalty_tier(
+    settings: &Settings,
+    stats: &mut Stats,
+    user_info: &mut UserInfo,
+    previous_stake_amount: u64,
+) -> Result<()> {
+    if let Some(tier) = calculate_position_loyalty_tier(stats, user_info) {
+        let tier_staked = &mut stats.loyalty_tier_staked[tier as usize];
+        *tier_staked = tier_staked
+            .checked_sub(previous_stake_amount)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+
+    user_info.loyalty_schedule_epoch = stats.loyalty_schedule_epoch;
+    user_info.loyalty_tier = calculate_loyalty_tier(settings, user_info, stats.last_update_time);
+
+    if let Some(tier) = user_info.loyalty_tier {
+        let tier_staked = &mut stats.loyalty_tier_staked[tier as usize];
+        *tier_staked = tier_staked
+            .checked_add(user_info.stake_amount)
+            .ok_or(StakingError::MathOverflow)?;
+        user_info.loyalty_reward_per_token_paid_numerator =
+            stats.loyalty_reward_per_token_stored_numerator[tier as usize];
+    }
+
+    Ok(())
+}
+
+// Loyalty rewards accrued since the last capture, as if the accumulators were updated at current_time
+fn calculate_uncaptured_loyalty_reward(
+    settings: &Settings,
+    stats: &Stats,
+    user_info: &UserInfo,
+    current_time: i64,
+) -> Result<u64> {
+    let (_, loyalty_reward_increment_numerators) =
+        calculate_reward_increment_numerators(settings, stats, current_time)?;
+    let mut current_loyalty_reward_per_token_stored_numerators =
+        stats.loyalty_reward_per_token_stored_numerator;
+    for (stored_numerator, increment_numerator) in
+        current_loyalty_reward_per_token_stored_numerators
+            .iter_mut()
+            .zip(loyalty_reward_increment_numerators)
+    {
+        *stored_numerator = stored_numerator
+            .checked_add(increment_numerator)
+            .ok_or(StakingError::MathOverflow)?;
+    }
+
+    let (loyalty_reward, _, _) = calculate_accrued_loyalty_reward(
+        settings,
+        stats,
+        user_info,
+        &current_loyalty_reward_per_token_stored_numerators,
+        current_time,
+    )?;
+    Ok(loyalty_reward)
+}
+
+fn calculate_current_reward_per_token_stored_numerator(
+    settings: &Settings,
+    stats: &Stats,
+    current_time: i64,
+) -> Result<u128> {
+    let (reward_increment_numerator, _) =
+        calculate_reward_increment_numerators(settings, stats, current_time)?;
+
+    Ok(stats
+        .reward_per_token_stored_numerator
+        .checked_add(reward_increment_numerator)
+        .ok_or(StakingError::MathOverflow)?)
+}
+
+// Rewards stop accruing after the rewards end time, or while solvency mode halts the accrual
+fn is_accrual_paused(settings: &Settings, stats: &Stats, current_time: i64) -> Result<bool> {
+    let accrual_ended = settings
+        .rewards_end_time
+        .is_some_and(|rewards_end_time| current_time >= rewards_end_time);
+    let accrual_halted = settings.solvency_mode_enabled
+        && calculate_unallocated_rewards(settings, stats, current_time)? <= 0;
+
+    Ok(accrual_ended || accrual_halted)
+}
+
+// Inverse of calculate_reward_rate_per_second_per_token_numerator. Rounded up, to get back the configured value from the truncated rate.
+fn calculate_reward_rate_yearly_percentage_numerator(
+    reward_rate_per_second_per_token_numerator: u128,
+) -> Result<u64> {
+    let reward_rate_yearly_percentage_numerator = reward_rate_per_second_per_token_numerator
+        .checked_mul(SECONDS_PER_YEAR as u128)
+        .ok_or(StakingError::MathOverflow)?
+        .div_ceil(REWARD_PRECISION / PRECISION as u128);
+
+    Ok(u64::try_from(reward_rate_yearly_percentage_numerator)
+        .map_err(|_| StakingError::MathOverflow)?)
+}
+
+// APR a position in the loyalty tier is earning right now, its bonus included, with 1e12 precision
+fn calculate_effective_reward_rate_yearly_percentage_numerator(
+    settings: &Settings,
+    stats: &Stats,
+    loyalty_tier: Option<u8>,
+    current_time: i64,
+) -> Result<u64> {
+    if is_accrual_paused(settings, stats, current_time)? {
+        return Ok(0);
+    }
+
+    let bonus_rate_per_second_per_token_numerator = loyalty_tier
+        .and_then(|tier| settings.loyalty_tiers.get(tier as usize))
+        .map_or(0, |tier| tier.bonus_rate_per_second_per_token_numerator);
+
+    calculate_reward_rate_yearly_percentage_numerator(
+        settings
+            .reward_rate_per_second_per_token_numerator
+            .checked_add(bonus_rate_per_second_per_token_numerator)
+            .ok_or(StakingError::MathOverflow)?,
+    )
+}
+
+// Unallocated rewards, or 0 if more rewards are promised than provided
+fn calculate_available_rewards(
+    settings: &Settings,
+    stats: &Stats,
+    current_time: i64,
+) -> Result<u64> {
+    let unallocated_rewards = calculate_unallocated_rewards(settings, stats, current_time)?;
+    if unallocated_rewards > 0 {
+        Ok(u64::try_from(unallocated_rewards).map_err(|_| StakingError::MathOverflow)?)
+    } else {
+        Ok(0)
+    }
+}
+
+// Seconds until the unallocated rewards run out at the current rate. u64::MAX if they never do.
+fn calculate_reward_runway(settings: &Settings, stats: &Stats, current_time: i64) -> Result<u64> {
+    let available_rewards = calculate_available_rewards(settings, stats, current_time)?;
+
+    // Calculate current reward consumption rate per second, loyalty tiers included
+    let mut rewards_per_second = mul_div(
+        stats.total_staked,
+        settings.reward_rate_per_second_per_token_numerator,
+        REWARD_PRECISION,
+    )
+    .ok_or(StakingError::MathOverflow)?;
+    for (index, tier) in settings.loyalty_tiers.iter().enumerate() {
+        rewards_per_second = mul_div(
+            stats.loyalty_tier_staked[index],
+            tier.bonus_rate_per_second_per_token_numerator,
+            REWARD_PRECISION,
+        )
+        .and_then(|tier_rewards_per_second| rewards_per_second.checked_add(tier_rewards_per_second))
+        .ok_or(StakingError::MathOverflow)?;
+    }
+
+    let accrual_ended = settings
+        .rewards_end_time
+        .is_some_and(|rewards_end_time| current_time >= rewards_end_time);
+
+    if rewards_per_second == 0 || accrual_ended {
+        return Ok(u64::MAX);
+    }
+
+    let runway_seconds = (available_rewards as u128) / rewards_per_second;
+    let runway_seconds = if runway_seconds > u32::MAX as u128 {
+        u64::MAX
+    } else {
+        runway_seconds as u64
+    };
+
+    // Pool is never exhausted, if accrual stops before the rewards run out
+    let runway_seconds = match settings.rewards_end_time {
+        Some(rewards_end_time)
+            if i64::try_from(runway_seconds).map_or(true, |runway_seconds| {
+                current_time.saturating_add(runway_seconds) >= rewards_end_time
+            }) =>
+        {
+            u64::MAX
+        }
+        _ => runway_seconds,
+    };
+
+    Ok(runway_seconds)
+}
+
+fn calculate_uncaptured_rewards(
+    stake_amount: u64,
+    reward_per_token_stored_numerator: u128,
+    reward_per_token_paid_numerator: u128,
+) -> Result<u64> {
+    let reward_per_token_diff = reward_per_token_stored_numerator
+        .checked_sub(reward_per_token_paid_numerator)
+        .ok_or(StakingError::MathOverflow)?;
+
+    calculate_reward_amount(stake_amount, reward_per_token_diff)
+}
+
+// Promised rewards as if the accumulators were updated at current_time
+fn calculate_total_promised_rewards(
+    settings: &Settings,
+    stats: &Stats,
+    current_time: i64,
+) -> Result<u64> {
+    let (reward_increment_numerator, loyalty_reward_increment_numerators) =
+        calculate_reward_increment_numerators(settings, stats, current_time)?;
+
+    Ok(stats
+        .total_reward_promised
+        .checked_add(calculate_promised_reward_increment(
+            stats,
+            reward_increment_numerator,
+            &loyalty_reward_increment_numerators,
+        )?)
+        .ok_or(StakingError::MathOverflow)?)
+}
+
+fn calculate_unallocated_rewards(
+    settings: &Settings,
+    stats: &Stats,
+    current_time: i64,
+) -> Result<i128> {
+    let total_promised = calculate_total_promised_rewards(settings, stats, current_time)?;
+    // Forfeited rewards were promised, but will never be paid out.
+    // All the values are u64, so the i128 math can not overflow.
+    let total_liability = total_promised as i128 - stats.total_reward_forfeited as i128;
+    Ok(stats.total_reward_provided as i128 - total_liability)
+}
+
+// Advances the event sequence. Call after every state change, so the snapshot reflects the post-state.
+fn next_event_metadata(stats: &mut Stats) -> Result<EventMetadata> {
+    stats.event_sequence = stats
+        .event_sequence
+        .checked_add(1)
+        .ok_or(StakingError::MathOverflow)?;
+    let clock = Clock::get()?;
+
+    Ok(EventMetadata {
+        sequence: stats.event_sequence,
+        timestamp: clock.unix_timestamp,
+        slot: clock.slot,
+        total_staked: stats.total_staked,
+        total_withdrawal_request_amount: stats.total_withdrawal_request_amount,
+        reward_per_token_stored_numerator: stats.reward_per_token_stored_numerator,
+    })
+}
+
+// Grows a legacy layout account to the new size. The payer covers the missing rent.
+fn resize_account<'info>(
+    account: &AccountInfo<'info>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    new_space: usize,
+) -> Result<()> {
+    let missing_lamports = Rent::get()?
+        .minimum_balance(new_space)
+        .saturating_sub(account.lamports());
+    if missing_lamports > 0 {
+        system_program::transfer(
+            CpiContext::new(
+                system_program.to_account_info(),
+                system_program::Transfer {
+                    from: payer.to_account_info(),
+                    to: account.clone(),
+                },
+            ),
+            missing_lamports,
+        )?;
+    }
+
+    account.resize(new_space)?;
+
+    Ok(())
+}
+
+fn count_stake_checkpoints(stake_history: &AccountInfo) -> usize {
+    (stake_history.data_len() - StakeHistory::space(0)) / StakeCheckpoint::INIT_SPACE
+}
+
+// Offset of the checkpoint with the given index, where index 0 is the oldest checkpoint kept.
+// Once the ring is full, the position of the oldest checkpoint moves with every new one.
+fn stake_checkpoint_offset(stake_history: &StakeHistory, count: usize, index: usize) -> usize {
+    StakeHistory::space((stake_history.oldest_checkpoint_position as usize + index) % count)
+}
+
+fn read_stake_checkpoint(
+    stake_history: &Account<StakeHistory>,
+    index: usize,
+) -> Result<StakeCheckpoint> {
+    let account = stake_history.to_account_info();
+    let offset = stake_checkpoint_offset(stake_history, count_stake_checkpoints(&account), index);
+    let checkpoint = StakeCheckpoint::deserialize(&mut &account.try_borrow_data()?[offset..])?;
+    Ok(checkpoint)
+}
+
+fn write_stake_checkpoint(
+    stake_history: &Account<StakeHistory>,
+    index: usize,
+    checkpoint: &StakeCheckpoint,
+) -> Result<()> {
+    let account = stake_history.to_account_info();
+    let offset = stake_checkpoint_offset(stake_history, count_stake_checkpoints(&account), index);
+    checkpoint.serialize(&mut &mut account.try_borrow_mut_data()?[offset..])?;
+    Ok(())
+}
+
+// Records the balance after a change. Changes within the same slot only keep the final balance.
+// The history grows up to max_checkpoints, as long as the payer can cover the rent, and overwrites its oldest
+// checkpoint otherwise, so that the bookkeeping never blocks an exit. Every later push retries the growth,
+// so a payer short of lamports can not freeze the size of the ring.
+fn push_stake_checkpoint<'info>(
+    stake_history: &mut Account<'info, StakeHistory>,
+    payer: &Signer<'info>,
+    system_program: &Program<'info, System>,
+    max_checkpoints: usize,
+    amount: u64,
+) -> Result<()> {
+    let clock = Clock::get()?;
+    let account = stake_history.to_account_info();
+    let count = count_stake_checkpoints(&account);
+
+    let mut checkpoint = StakeCheckpoint {
+        timestamp: clock.unix_timestamp,
+        slot: clock.slot,
+        amount,
+    };
+    if let Some(last_index) = count.checked_sub(1) {
+        let last = read_stake_checkpoint(stake_history, last_index)?;
+        // Keeps the checkpoints ordered for the lookup, even if the cluster time drifts back
+        checkpoint.timestamp = checkpoint.timestamp.max(last.timestamp);
+        if last.slot == clock.slot {
+            return write_stake_checkpoint(stake_history, last_index, &checkpoint);
+        }
+    }
+
+    let can_grow = if count < max_checkpoints {
+        let rent = Rent::get()?;
+        let missing_lamports = rent
+            .minimum_balance(StakeHistory::space(count + 1))
+            .saturating_sub(account.lamports());
+        // The payer has to stay rent exempt itself
+        payer.lamports() >= missing_lamports.saturating_add(rent.minimum_balance(0))
+    } else {
+        false
+    };
+
+    stake_history.total_checkpoints = stake_history
+        .total_checkpoints
+        .checked_add(1)
+        .ok_or(StakingError::MathOverflow)?;
+    // The first checkpoint has nothing to overwrite
+    if can_grow || count == 0 {
+        resize_account(
+            &account,
+            payer,
+            system_program,
+            StakeHistory::space(count + 1),
+        )?;
+        insert_stake_checkpoint(stake_history, count, &checkpoint)
+    } else {
+        // Takes the place of the oldest checkpoint
+        stake_history.oldest_checkpoint_position =
+            (stake_history.oldest_checkpoint_position + 1) % count as u32;
+        write_stake_checkpoint(stake_history, count - 1, &checkpoint)
+    }
+}
+
+// Adds the newest checkpoint to a ring of count checkpoints, already resized to one more.
+// It goes right before the oldest checkpoint, which moves up by one place, together with the ones after it.
+fn insert_stake_checkpoint(
+    stake_history: &mut Account<StakeHistory>,
+    count: usize,
+    checkpoint: &StakeCheckpoint,
+) -> Result<()> {
+    let oldest = stake_history.oldest_checkpoint_position as usize;
+    if oldest > 0 {
+        let account = stake_history.to_account_info();
+        account.try_borrow_mut_data()?.copy_within(
+            StakeHistory::space(oldest)..StakeHistory::space(count),
+            StakeHistory::space(oldest + 1),
+        );
+        stake_history.oldest_checkpoint_position += 1;
+    }
+    write_stake_checkpoint(stake_history, count, checkpoint)
+}
+
+// Balance recorded by the last checkpoint at or before the timestamp. Nothing was staked before the first one.
+fn find_staked_amount_at_time(
+    stake_history: &Account<StakeHistory>,
+    timestamp: i64,
+) -> Result<u64> {
+    let count = count_stake_checkpoints(&stake_history.to_account_info());
+    let mut low = 0;
+    let mut high = count;
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if read_stake_checkpoint(stake_history, middle)?.timestamp <= timestamp {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+
+    match low.checked_sub(1) {
+        Some(index) => Ok(read_stake_checkpoint(stake_history, index)?.amount),
+        // The balance before the oldest checkpoint kept is only known, if none was overwritten
+        None if stake_history.total_checkpoints > count as u64 => {
+            err!(StakingError::StakeHistoryPruned)
+        }
+        None => Ok(0),
+    }
+}
+
+// Layout version of an account, readable before the account is migrated.
+// Accounts created by v1.0.0 have no version field, and are recognized by their size.
+fn read_account_version<T: Discriminator>(account: &AccountInfo, v1_space: usize) -> Result<u8> {
+    let data = account.try_borrow_data()?;
+    require!(
+        data.starts_with(T::DISCRIMINATOR),
+        anchor_lang::error::ErrorCode::AccountDiscriminatorMismatch
+    );
+
+    if data.len() == 8 + v1_space {
+        return Ok(1);
+    }
+
+    Ok(data[8])
+}
+
+// Administrator is the first field of Settings in every layout, after the version field since v2
+fn read_administrator(settings: &AccountInfo) -> Result<Pubkey> {
+    let offset = match read_account_version::<Settings>(settings, SettingsV1::SPACE)? {
+        1 => 8,
+        _ => 8 + 1,
+    };
+
+    Ok(Pubkey::deserialize(
+        &mut &settings.try_borrow_data()?[offset..],
+    )?)
+}
+
+// ===========================================
+// ============ ACCOUNT STRUCTURES ===========
+// ===========================================
+
+#[account]
+#[derive(InitSpace)]
+pub struct Settings {
+    pub version: u8,
+    pub administrator: Pubkey,
+    pub pending_administrator: Option<Pubkey>,
+    pub token_mint: Pubkey,
+    pub withdrawal_delay_seconds: u32,
+    pub reward_rate_per_second_per_token_numerator: u128,
+    pub rewards_end_time: Option<i64>,
+    pub solvency_mode_enabled: bool,
+    pub partial_reward_payout_enabled: bool,
+    pub voter_weight_age_bonus_numerator: u64,
+    pub voter_weight_age_bonus_saturation_seconds: u32,
+    #[max_len(MAX_LOYALTY_TIERS)]
+    pub loyalty_tiers: Vec<LoyaltyTier>,
+    #[max_len(MAX_LOYALTY_TIERS)]
+    pub previous_loyalty_tiers: Vec<LoyaltyTier>, // Still paid to the positions that joined them, until their next capture
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct Stats {
+    pub version: u8,
+    pub reward_per_token_stored_numerator: u128,
+    pub last_update_time: i64,
+    pub total_staked: u64,
+    pub total_reward_promised: u64,
+    pub total_reward_provided: u64,
+    pub total_reward_debt: u64,
+    pub total_reward_forfeited: u64,
+    pub total_withdrawal_request_amount: u64,
+    pub total_withdrawal_request_reward_amount: u64,
+    pub active_stakers: u64,
+    pub total_positions_opened: u64,
+    pub lifetime_staked: u64,
+    pub lifetime_withdrawn: u64,
+    pub lifetime_rewards_paid: u64,
+    pub event_sequence: u64, // Sequence number of the last emitted event
+    // Loyalty tiers, by their index in the schedule
+    pub loyalty_tier_staked: [u64; MAX_LOYALTY_TIERS], // Tokens of the positions in the tier
+    pub loyalty_reward_per_token_stored_numerator: [u128; MAX_LOYALTY_TIERS], // Bonus accumulator of the tier
+    pub loyalty_schedule_epoch: u64, // Bumped by every schedule change, which empties the tiers
+    pub loyalty_schedule_start_time: i64, // The previous schedule paid its bonus until then
+    pub previous_loyalty_reward_per_token_stored_numerator: [u128; MAX_LOYALTY_TIERS], // Accumulators at the change
+}
+
+#[account]
 #[derive(InitSpace)]
 pub struct UserInfo {
+    pub version: u8,
+    pub user: Pubkey,
+    pub stake_amount: u64,
+    pub staked_at: i64,
+    pub reward_per_token_paid_numerator: u128,
+    pub captured_reward: u64,
+    pub withdrawal_request_time: i64,
+    pub withdrawal_request_amount: u64,
+    pub withdrawal_request_reward_amount: u64,
+    pub reward_debt_amount: u64,
+    pub lifetime_staked: u64,
+    pub lifetime_withdrawn: u64,
+    pub lifetime_rewards_paid: u64,
+    pub lifetime_rewards_forfeited: u64,
+    pub loyalty_tier: Option<u8>, // Tier the position earns the bonus of, joined at the last capture
+    pub loyalty_reward_per_token_paid_numerator: u128,
+    pub loyalty_schedule_epoch: u64, // Schedule the tier was joined under. Memberships of older ones no longer count.
+    pub bump: u8, // Of the [b"user_info", user] address, so batched views do not search for it
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, InitSpace)]
+pub struct LoyaltyTier {
+    pub min_staked_seconds: u32, // Continuous stake age, from which the bonus applies
+    pub bonus_rate_per_second_per_token_numerator: u128, // Paid on top of the reward rate
+}
+
+// Layout versions. Bumped with every layout change, together with a new arm in the migration instruction.
+impl Settings {
+    pub const VERSION: u8 = 2;
+}
+
+impl Stats {
+    pub const VERSION: u8 = 2;
+}
+
+impl UserInfo {
+    pub const VERSION: u8 = 2;
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct RateHistory {
+    #[max_len(0)] // Starts with the initial rate, grows by one checkpoint with every rate change
+    pub checkpoints: Vec<RateCheckpoint>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, InitSpace)]
+pub struct RateCheckpoint {
+    pub timestamp: i64, // The new rate applies from this time on
+    pub old_reward_rate_per_second_per_token_numerator: u128,
+    pub new_reward_rate_per_second_per_token_numerator: u128,
+    pub reward_per_token_stored_numerator: u128, // Accumulator at the moment of the change
+}
+
+impl RateHistory {
+    pub fn space(checkpoints: usize) -> usize {
+        8 + RateHistory::INIT_SPACE + checkpoints * RateCheckpoint::INIT_SPACE
+    }
+}
+
+// Checkpoints follow the account as raw entries, in a ring of up to MAX_USER_STAKE_CHECKPOINTS
+// or MAX_TOTAL_STAKE_CHECKPOINTS. They are read and written in place, so a history is never deserialized as a whole.
+#[account]
+#[derive(InitSpace)]
+pub struct StakeHistory {
+    pub total_checkpoints: u64, // Recorded since the creation. Beyond the ring size, the oldest ones are overwritten.
+    pub oldest_checkpoint_position: u32, // Place of the oldest checkpoint kept in the ring
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug, InitSpace)]
+pub struct StakeCheckpoint {
+    pub timestamp: i64,
+    pub slot: u64,
+    pub amount: u64, // Staked balance from this checkpoint on
+}
+
+impl StakeHistory {
+    pub fn space(checkpoints: usize) -> usize {
+        8 + StakeHistory::INIT_SPACE + checkpoints * StakeCheckpoint::INIT_SPACE
+    }
+}
+
+// spl-governance addin layout. Anchor derives the same discriminator the addin API expects.
+#[account]
+#[derive(InitSpace)]
+pub struct VoterWeightRecord {
+    pub realm: Pubkey,
+    pub governing_token_mint: Pubkey,
+    pub governing_token_owner: Pubkey,
+    pub voter_weight: u64,
+    pub voter_weight_expiry: Option<u64>, // Slot
+    pub weight_action: Option<VoterWeightAction>,
+    pub weight_action_target: Option<Pubkey>,
+    pub reserved: [u8; 8],
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Copy, Debug, PartialEq, Eq, InitSpace)]
+pub enum VoterWeightAction {
+    CastVote,
+    CommentProposal,
+    CreateGovernance,
+    CreateProposal,
+    SignOffProposal,
+}
+
+#[account]
+#[derive(InitSpace)]
+pub struct RewardStream {
+    pub treasury_token_account: Pubkey,
+    pub rate_per_second: u64,
+    pub cap: u64,
+    pub total_pulled: u64,
+    pub last_pull_time: i64,
+}
+
+// Stats layout deployed in v1.0.0. Only used to migrate the existing account.
+#[derive(AnchorDeserialize)]
+pub struct StatsV1 {
+    pub reward_per_token_stored_numerator: u64,
+    pub last_update_time: u32,
+    pub total_staked: u64,
+    pub total_reward_promised: u64,
+    pub total_reward_provided: u64,
+}
+
+impl StatsV1 {
+    pub const SPACE: usize = 8 + 4 + 8 + 8 + 8;
+}
+
+// Settings layout deployed in v1.0.0. Only used to migrate the existing account.
+#[derive(AnchorDeserialize)]
+pub struct SettingsV1 {
+    pub administrator: Pubkey,
+    pub pending_administrator: Option<Pubkey>,
+    pub token_mint: Pubkey,
+    pub withdrawal_delay_seconds: u32,
+    pub reward_rate_per_second_per_token_numerator: u64,
+}
+
+impl SettingsV1 {
+    pub const SPACE: usize = 32 + (1 + 32) + 32 + 4 + 8;
+}
+
+// UserInfo layout deployed in v1.0.0. Only used to migrate the existing accounts.
+#[derive(AnchorDeserialize)]
+pub struct UserInfoV1 {
     pub user: Pubkey,
     pub stake_amount: u64,
     pub staked_at: u32,
@@ -643,56 +2963,808 @@ pub struct UserInfo {
     pub withdrawal_request_reward_amount: u64,
 }
 
-// ===========================================
-// ============ ACCOUNT CONTEXTS =============
-// ===========================================
+impl UserInfoV1 {
+    pub const SPACE: usize = 32 + 8 + 4 + 8 + 8 + 4 + 8 + 8;
+}
+
+// Values of the new Stats fields, which cannot be derived on-chain. Reconstructed off-chain from user accounts and history.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct StatsMigrationArgs {
+    pub total_reward_forfeited: u64,
+    pub total_withdrawal_request_amount: u64,
+    pub total_withdrawal_request_reward_amount: u64,
+    pub active_stakers: u64,
+    pub total_positions_opened: u64,
+    pub lifetime_staked: u64,
+    pub lifetime_withdrawn: u64,
+    pub lifetime_rewards_paid: u64,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct LoyaltyTierArgs {
+    pub min_staked_days: u64,
+    pub bonus_rate_yearly_percentage_numerator: u64, // e.g., 10_000_000_000 for +1%
+}
+
+// Returned by view_position
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PositionView {
+    pub owner: Pubkey,
+    pub stake_amount: u64,
+    pub staked_at: i64,
+    pub captured_reward: u64,
+    pub uncaptured_reward: u64,
+    pub withdrawal_request_amount: u64,
+    pub withdrawal_request_reward_amount: u64,
+    pub reward_debt_amount: u64,
+    pub withdrawal_unlock_time: Option<i64>, // None without a withdrawal request
+    pub can_withdraw: bool,
+    pub loyalty_tier: Option<u8>, // Tier the position earns the bonus of
+    pub reached_loyalty_tier: Option<u8>, // Tier of its current age, joined with the next capture
+    pub effective_reward_rate_yearly_percentage_numerator: u64, // Loyalty bonus included
+}
+
+// Returned by view_pool
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PoolView {
+    pub reward_rate_yearly_percentage_numerator: u64,
+    pub reward_rate_per_second_per_token_numerator: u128,
+    pub total_staked: u64,
+    pub total_reward_promised: u64,
+    pub total_reward_provided: u64,
+    pub protocol_token_account_amount: u64,
+    pub unallocated_rewards: i128,
+    pub runway_seconds: u64,
+    pub withdrawal_delay_seconds: u32,
+    pub rewards_end_time: Option<i64>,
+    pub accrual_paused: bool,
+    pub solvency_mode_enabled: bool,
+    pub partial_reward_payout_enabled: bool,
+}
+
+// Returned by view_projected_rewards
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct ProjectedRewardsView {
+    pub owner: Pubkey,
+    pub timestamp: i64,
+    pub captured_reward: u64,
+    pub uncaptured_reward: u64,
+    pub withdrawal_request_reward_amount: u64,
+    pub total_reward: u64,
+    pub unallocated_rewards: i128,
+}
+
+// Returned by view_solvency
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct SolvencyView {
+    pub protocol_token_account_amount: u64,
+    pub total_staked: u64,
+    pub total_withdrawal_request_amount: u64,
+    pub user_vault_amount: u64, // Implied balance of the users' own token accounts
+    pub total_withdrawal_request_reward_amount: u64,
+    pub total_reward_debt: u64,
+    pub reward_liability: u64,
+    pub reward_surplus: i128,
+    pub collateralization_ratio_numerator: u64, // 1e12 precision, u64::MAX without a liability
+}
+
+// Returned by view_positions
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PositionsView {
+    pub timestamp: i64,
+    pub reward_per_token_stored_numerator: u128, // Accumulator value shared by all positions
+    pub positions: Vec<PositionRewardsView>,
+}
+
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct PositionRewardsView {
+    pub owner: Pubkey,
+    pub stake_amount: u64,
+    pub captured_reward: u64,
+    pub uncaptured_reward: u64,
+    pub withdrawal_request_reward_amount: u64,
+    pub total_reward: u64,
+}
+
+// Returned by view_reward_rate_at
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct RewardRateView {
+    pub timestamp: i64,
+    pub reward_rate_yearly_percentage_numerator: u64,
+    pub reward_rate_per_second_per_token_numerator: u128,
+    pub effective_since: Option<i64>, // None before the first recorded change
+}
+
+// ===========================================
+// ============ ACCOUNT CONTEXTS =============
+// ===========================================
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct InitializeAccounts<'info> {
+    #[account(mut)]
+    pub administrator: Signer<'info>,
+
+    // Only the upgrade authority can initialize, so a fresh deployment can not be front-run
+    #[account(
+        seeds = [crate::ID.as_ref()],
+        bump,
+        seeds::program = ProgramData::owner(),
+        constraint = program_data.upgrade_authority_address == Some(administrator.key())
+            @ StakingError::UnauthorizedInitialization
+    )]
+    pub program_data: Account<'info, ProgramData>,
+
+    #[account(
+        init,
+        payer = administrator,
+        space = 8 + Settings::INIT_SPACE,
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        init,
+        payer = administrator,
+        space = 8 + Stats::INIT_SPACE,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        init,
+        payer = administrator,
+        space = RateHistory::space(1),
+        seeds = [b"rate_history"],
+        bump
+    )]
+    pub rate_history: Account<'info, RateHistory>,
+
+    #[account(
+        init,
+        payer = administrator,
+        associated_token::mint = token_mint,
+        associated_token::authority = settings,
+    )]
+    pub protocol_token_account: Account<'info, TokenAccount>,
+
+    pub token_mint: Account<'info, Mint>,
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct AddRewardsAccounts<'info> {
+    #[account(mut)]
+    pub administrator: Signer<'info>,
+
+    #[account(
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = administrator,
+    )]
+    pub admin_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = settings,
+    )]
+    pub protocol_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureRewardRatioAccounts<'info> {
+    #[account(mut)]
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    // Created by initialize or migrate_settings
+    #[account(
+        mut,
+        seeds = [b"rate_history"],
+        bump
+    )]
+    pub rate_history: Account<'info, RateHistory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureWithdrawalDelayAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureRewardsEndTimeAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureSolvencyModeAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigurePartialRewardPayoutAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct InitiateOwnershipTransferAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct FinalizeOwnershipTransferAccounts<'info> {
+    pub new_administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureRewardStreamAccounts<'info> {
+    #[account(mut)]
+    pub administrator: Signer<'info>,
+
+    #[account(
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        init_if_needed,
+        payer = administrator,
+        space = 8 + RewardStream::INIT_SPACE,
+        seeds = [b"reward_stream"],
+        bump
+    )]
+    pub reward_stream: Account<'info, RewardStream>,
+
+    #[account(
+        token::mint = settings.token_mint,
+    )]
+    pub treasury_token_account: Account<'info, TokenAccount>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureVoterWeightAgeBonusAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct ConfigureLoyaltyScheduleAccounts<'info> {
+    pub administrator: Signer<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        has_one = administrator
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct MigrateStatsAccounts<'info> {
+    #[account(mut)]
+    pub administrator: Signer<'info>,
+
+    /// CHECK: Can not be deserialized before its own migration. Administrator is read manually.
+    #[account(
+        seeds = [b"settings"],
+        bump,
+        owner = crate::ID
+    )]
+    pub settings: UncheckedAccount<'info>,
+
+    /// CHECK: Old layout, deserialized manually
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump,
+        owner = crate::ID
+    )]
+    pub stats: UncheckedAccount<'info>,
+
+    #[account(
+        init_if_needed,
+        payer = administrator,
+        space = StakeHistory::space(0),
+        seeds = [b"total_stake_history"],
+        bump
+    )]
+    pub total_stake_history: Account<'info, StakeHistory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct MigrateSettingsAccounts<'info> {
+    #[account(mut)]
+    pub administrator: Signer<'info>,
+
+    /// CHECK: Old layout, deserialized manually
+    #[account(
+        mut,
+        seeds = [b"settings"],
+        bump,
+        owner = crate::ID
+    )]
+    pub settings: UncheckedAccount<'info>,
+
+    // Migrated before the settings
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    // Deployments before v2 have no rate history yet. Once migrated, the handler fails before touching it.
+    #[account(
+        init_if_needed,
+        payer = administrator,
+        space = RateHistory::space(1),
+        seeds = [b"rate_history"],
+        bump
+    )]
+    pub rate_history: Account<'info, RateHistory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+#[instruction(realm: Pubkey)]
+pub struct UpdateVoterWeightRecordAccounts<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: Only used to derive the user info and voter weight record addresses
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        seeds = [b"user_info", user.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = 8 + VoterWeightRecord::INIT_SPACE,
+        seeds = [b"voter_weight_record", realm.as_ref(), user.key().as_ref()],
+        bump
+    )]
+    pub voter_weight_record: Account<'info, VoterWeightRecord>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct UpdateLoyaltyTierAccounts<'info> {
+    /// CHECK: Only used to derive the user info address
+    pub user: UncheckedAccount<'info>,
+
+    #[account(
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        mut,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct MigrateUserInfoAccounts<'info> {
+    #[account(mut)]
+    pub payer: Signer<'info>,
+
+    /// CHECK: Only used to derive the user info address
+    pub user: UncheckedAccount<'info>,
+
+    /// CHECK: Old layout, deserialized manually
+    #[account(
+        mut,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump,
+        owner = crate::ID
+    )]
+    pub user_info: UncheckedAccount<'info>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        init_if_needed,
+        payer = payer,
+        space = StakeHistory::space(0),
+        seeds = [b"stake_history", user.key().as_ref()],
+        bump
+    )]
+    pub user_stake_history: Account<'info, StakeHistory>,
+
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct StakeAccounts<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = 8 + UserInfo::INIT_SPACE,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump
+    )]
+    pub user_info: Account<'info, UserInfo>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = user,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        associated_token::mint = token_mint,
+        associated_token::authority = user_info,
+    )]
+    pub user_info_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        constraint = token_mint.key() == settings.token_mint
+    )]
+    pub token_mint: Account<'info, Mint>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = StakeHistory::space(0),
+        seeds = [b"stake_history", user.key().as_ref()],
+        bump
+    )]
+    pub user_stake_history: Account<'info, StakeHistory>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = StakeHistory::space(0),
+        seeds = [b"total_stake_history"],
+        bump
+    )]
+    pub total_stake_history: Account<'info, StakeHistory>,
+
+    pub token_program: Program<'info, Token>,
+    pub associated_token_program: Program<'info, AssociatedToken>,
+    pub system_program: Program<'info, System>,
+}
+
+#[derive(Accounts)]
+#[event_cpi]
+pub struct RequestWithdrawalAccounts<'info> {
+    #[account(mut)]
+    pub user: Signer<'info>,
+
+    #[account(
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        mut,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = StakeHistory::space(0),
+        seeds = [b"stake_history", user.key().as_ref()],
+        bump
+    )]
+    pub user_stake_history: Account<'info, StakeHistory>,
+
+    #[account(
+        init_if_needed,
+        payer = user,
+        space = StakeHistory::space(0),
+        seeds = [b"total_stake_history"],
+        bump
+    )]
+    pub total_stake_history: Account<'info, StakeHistory>,
+
+    pub system_program: Program<'info, System>,
+}
 
 #[derive(Accounts)]
-pub struct InitializeAccounts<'info> {
-    #[account(mut)]
-    pub administrator: Signer<'info>,
+#[event_cpi]
+pub struct WithdrawAccounts<'info> {
+    pub user: Signer<'info>,
 
     #[account(
-        init,
-        payer = administrator,
-        space = 8 + Settings::INIT_SPACE,
         seeds = [b"settings"],
         bump
     )]
     pub settings: Account<'info, Settings>,
 
     #[account(
-        init,
-        payer = administrator,
-        space = 8 + Stats::INIT_SPACE,
+        mut,
         seeds = [b"stats"],
         bump
     )]
     pub stats: Account<'info, Stats>,
 
     #[account(
-        init,
-        payer = administrator,
-        associated_token::mint = token_mint,
+        mut,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = user,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = user_info,
+    )]
+    pub user_info_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
         associated_token::authority = settings,
     )]
     pub protocol_token_account: Account<'info, TokenAccount>,
 
-    pub token_mint: Account<'info, Mint>,
     pub token_program: Program<'info, Token>,
     pub associated_token_program: Program<'info, AssociatedToken>,
     pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct AddRewardsAccounts<'info> {
+#[event_cpi]
+pub struct WithdrawAndForfeitRewardsAccounts<'info> {
     #[account(mut)]
-    pub administrator: Signer<'info>,
+    pub user: Signer<'info>,
 
     #[account(
         seeds = [b"settings"],
-        bump,
-        has_one = administrator
+        bump
     )]
     pub settings: Account<'info, Settings>,
 
@@ -703,12 +3775,26 @@ pub struct AddRewardsAccounts<'info> {
     )]
     pub stats: Account<'info, Stats>,
 
+    #[account(
+        mut,
+        seeds = [b"user_info", user.key().as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
+
     #[account(
         mut,
         associated_token::mint = settings.token_mint,
-        associated_token::authority = administrator,
+        associated_token::authority = user,
     )]
-    pub admin_token_account: Account<'info, TokenAccount>,
+    pub user_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = user_info,
+    )]
+    pub user_info_token_account: Account<'info, TokenAccount>,
 
     #[account(
         mut,
@@ -718,19 +3804,16 @@ pub struct AddRewardsAccounts<'info> {
     pub protocol_token_account: Account<'info, TokenAccount>,
 
     pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct ConfigureRewardRatioAccounts<'info> {
-    pub administrator: Signer<'info>,
+#[event_cpi]
+pub struct ClaimRewardDebtAccounts<'info> {
+    pub user: Signer<'info>,
 
     #[account(
-        mut,
         seeds = [b"settings"],
-        bump,
-        has_one = administrator
+        bump
     )]
     pub settings: Account<'info, Settings>,
 
@@ -740,51 +3823,69 @@ pub struct ConfigureRewardRatioAccounts<'info> {
         bump
     )]
     pub stats: Account<'info, Stats>,
-}
-
-#[derive(Accounts)]
-pub struct ConfigureWithdrawalDelayAccounts<'info> {
-    pub administrator: Signer<'info>,
 
     #[account(
         mut,
-        seeds = [b"settings"],
+        seeds = [b"user_info", user.key().as_ref()],
         bump,
-        has_one = administrator
     )]
-    pub settings: Account<'info, Settings>,
-}
+    pub user_info: Account<'info, UserInfo>,
 
-#[derive(Accounts)]
-pub struct InitiateOwnershipTransferAccounts<'info> {
-    pub administrator: Signer<'info>,
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = user,
+    )]
+    pub user_token_account: Account<'info, TokenAccount>,
 
     #[account(
         mut,
-        seeds = [b"settings"],
-        bump,
-        has_one = administrator
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = settings,
     )]
-    pub settings: Account<'info, Settings>,
+    pub protocol_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct FinalizeOwnershipTransferAccounts<'info> {
-    pub new_administrator: Signer<'info>,
+#[event_cpi]
+pub struct DonateRewardsAccounts<'info> {
+    pub donor: Signer<'info>,
 
     #[account(
-        mut,
         seeds = [b"settings"],
         bump
     )]
     pub settings: Account<'info, Settings>,
+
+    #[account(
+        mut,
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    // Any token account of the staking mint. Token program checks, that the donor is allowed to spend from it.
+    #[account(
+        mut,
+        token::mint = settings.token_mint,
+    )]
+    pub donor_token_account: Account<'info, TokenAccount>,
+
+    #[account(
+        mut,
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = settings,
+    )]
+    pub protocol_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
 }
 
 #[derive(Accounts)]
-pub struct StakeAccounts<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-
+#[event_cpi]
+pub struct PullRewardStreamAccounts<'info> {
     #[account(
         seeds = [b"settings"],
         bump
@@ -799,43 +3900,52 @@ pub struct StakeAccounts<'info> {
     pub stats: Account<'info, Stats>,
 
     #[account(
-        init_if_needed,
-        payer = user,
-        space = 8 + UserInfo::INIT_SPACE,
-        seeds = [b"user_info", user.key().as_ref()],
+        mut,
+        seeds = [b"reward_stream"],
         bump
     )]
-    pub user_info: Account<'info, UserInfo>,
+    pub reward_stream: Account<'info, RewardStream>,
+
+    #[account(
+        mut,
+        address = reward_stream.treasury_token_account,
+    )]
+    pub treasury_token_account: Account<'info, TokenAccount>,
 
     #[account(
         mut,
         associated_token::mint = settings.token_mint,
-        associated_token::authority = user,
+        associated_token::authority = settings,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub protocol_token_account: Account<'info, TokenAccount>,
+
+    pub token_program: Program<'info, Token>,
+}
 
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct ViewCurrentRewardsAccounts<'info> {
     #[account(
-        init_if_needed,
-        payer = user,
-        associated_token::mint = token_mint,
-        associated_token::authority = user_info,
+        seeds = [b"settings"],
+        bump
     )]
-    pub user_info_token_account: Account<'info, TokenAccount>,
+    pub settings: Account<'info, Settings>,
 
     #[account(
-        constraint = token_mint.key() == settings.token_mint
+        seeds = [b"stats"],
+        bump
     )]
-    pub token_mint: Account<'info, Mint>,
+    pub stats: Account<'info, Stats>,
 
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
+    #[account(
+        seeds = [b"user_info", owner.as_ref()],
+        bump,
+    )]
+    pub user_info: Account<'info, UserInfo>,
 }
 
 #[derive(Accounts)]
-pub struct RequestWithdrawalAccounts<'info> {
-    pub user: Signer<'info>,
-
+pub struct ViewUnallocatedRewardsAccounts<'info> {
     #[account(
         seeds = [b"settings"],
         bump
@@ -843,24 +3953,51 @@ pub struct RequestWithdrawalAccounts<'info> {
     pub settings: Account<'info, Settings>,
 
     #[account(
-        mut,
         seeds = [b"stats"],
         bump
     )]
     pub stats: Account<'info, Stats>,
+}
 
+#[derive(Accounts)]
+pub struct ViewRewardRunwayAccounts<'info> {
     #[account(
-        mut,
-        seeds = [b"user_info", user.key().as_ref()],
-        bump,
+        seeds = [b"settings"],
+        bump
     )]
-    pub user_info: Account<'info, UserInfo>,
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawAccounts<'info> {
-    pub user: Signer<'info>,
+pub struct ViewPendingWithdrawalsAccounts<'info> {
+    #[account(
+        seeds = [b"settings"],
+        bump
+    )]
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        associated_token::mint = settings.token_mint,
+        associated_token::authority = settings,
+    )]
+    pub protocol_token_account: Account<'info, TokenAccount>,
+}
 
+#[derive(Accounts)]
+#[instruction(owner: Pubkey)]
+pub struct ViewPositionAccounts<'info> {
     #[account(
         seeds = [b"settings"],
         bump
@@ -868,84 +4005,91 @@ pub struct WithdrawAccounts<'info> {
     pub settings: Account<'info, Settings>,
 
     #[account(
-        mut,
-        seeds = [b"user_info", user.key().as_ref()],
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
+
+    #[account(
+        seeds = [b"user_info", owner.as_ref()],
         bump,
     )]
     pub user_info: Account<'info, UserInfo>,
 
     #[account(
-        mut,
         associated_token::mint = settings.token_mint,
-        associated_token::authority = user,
+        associated_token::authority = settings,
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub protocol_token_account: Account<'info, TokenAccount>,
+}
 
+#[derive(Accounts)]
+pub struct ViewPoolAccounts<'info> {
     #[account(
-        mut,
-        associated_token::mint = settings.token_mint,
-        associated_token::authority = user_info,
+        seeds = [b"settings"],
+        bump
     )]
-    pub user_info_token_account: Account<'info, TokenAccount>,
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
 
     #[account(
-        mut,
         associated_token::mint = settings.token_mint,
         associated_token::authority = settings,
     )]
     pub protocol_token_account: Account<'info, TokenAccount>,
-
-    pub token_program: Program<'info, Token>,
-    pub associated_token_program: Program<'info, AssociatedToken>,
-    pub system_program: Program<'info, System>,
 }
 
 #[derive(Accounts)]
-pub struct WithdrawAndForfeitRewardsAccounts<'info> {
-    #[account(mut)]
-    pub user: Signer<'info>,
-
+#[instruction(owner: Pubkey)]
+pub struct ViewProjectedRewardsAccounts<'info> {
     #[account(
         seeds = [b"settings"],
         bump
     )]
-    pub settings: Account<'info, Settings>,
+    pub settings: Account<'info, Settings>,
+
+    #[account(
+        seeds = [b"stats"],
+        bump
+    )]
+    pub stats: Account<'info, Stats>,
 
     #[account(
-        mut,
-        seeds = [b"user_info", user.key().as_ref()],
+        seeds = [b"user_info", owner.as_ref()],
         bump,
     )]
     pub user_info: Account<'info, UserInfo>,
+}
 
+#[derive(Accounts)]
+pub struct ViewSolvencyAccounts<'info> {
     #[account(
-        mut,
-        associated_token::mint = settings.token_mint,
-        associated_token::authority = user,
+        seeds = [b"settings"],
+        bump
     )]
-    pub user_token_account: Account<'info, TokenAccount>,
+    pub settings: Account<'info, Settings>,
 
     #[account(
-        mut,
-        associated_token::mint = settings.token_mint,
-        associated_token::authority = user_info,
+        seeds = [b"stats"],
+        bump
     )]
-    pub user_info_token_account: Account<'info, TokenAccount>,
+    pub stats: Account<'info, Stats>,
 
     #[account(
-        mut,
         associated_token::mint = settings.token_mint,
         associated_token::authority = settings,
     )]
     pub protocol_token_account: Account<'info, TokenAccount>,
-
-    pub token_program: Program<'info, Token>,
 }
 
+// User info accounts are passed as remaining accounts
 #[derive(Accounts)]
-pub struct ViewCurrentRewardsAccounts<'info> {
-    pub user: Signer<'info>,
-
+pub struct ViewPositionsAccounts<'info> {
     #[account(
         seeds = [b"settings"],
         bump
@@ -957,16 +4101,10 @@ pub struct ViewCurrentRewardsAccounts<'info> {
         bump
     )]
     pub stats: Account<'info, Stats>,
-
-    #[account(
-        seeds = [b"user_info", user.key().as_ref()],
-        bump,
-    )]
-    pub user_info: Account<'info, UserInfo>,
 }
 
 #[derive(Accounts)]
-pub struct ViewUnallocatedRewardsAccounts<'info> {
+pub struct ViewRewardRateAtAccounts<'info> {
     #[account(
         seeds = [b"settings"],
         bump
@@ -974,25 +4112,29 @@ pub struct ViewUnallocatedRewardsAccounts<'info> {
     pub settings: Account<'info, Settings>,
 
     #[account(
-        seeds = [b"stats"],
+        seeds = [b"rate_history"],
         bump
     )]
-    pub stats: Account<'info, Stats>,
+    pub rate_history: Account<'info, RateHistory>,
 }
 
 #[derive(Accounts)]
-pub struct ViewRewardRunwayAccounts<'info> {
+#[instruction(owner: Pubkey)]
+pub struct ViewStakedAtTimeAccounts<'info> {
     #[account(
-        seeds = [b"settings"],
+        seeds = [b"stake_history", owner.as_ref()],
         bump
     )]
-    pub settings: Account<'info, Settings>,
+    pub stake_history: Account<'info, StakeHistory>,
+}
 
+#[derive(Accounts)]
+pub struct ViewTotalStakedAtTimeAccounts<'info> {
     #[account(
-        seeds = [b"stats"],
+        seeds = [b"total_stake_history"],
         bump
     )]
-    pub stats: Account<'info, Stats>,
+    pub total_stake_history: Account<'info, StakeHistory>,
 }
 
 // ===========================================
@@ -1017,12 +4159,40 @@ pub enum StakingError {
     MathOverflow,
     #[msg("Unauthorized ownership transfer")]
     UnauthorizedOwnershipTransfer,
+    #[msg("Settings are not approved as a delegate of the treasury")]
+    RewardStreamNotApproved,
+    #[msg("No reward debt found")]
+    NoRewardDebt,
+    #[msg("Account is already migrated")]
+    AccountAlreadyMigrated,
+    #[msg("Only the program upgrade authority can initialize the protocol")]
+    UnauthorizedInitialization,
+    #[msg("Timestamp is in the past")]
+    InvalidTimestamp,
+    #[msg("User info account does not match its seeds")]
+    InvalidUserInfo,
+    #[msg("Loyalty tiers must be ordered by a growing stake age, within the tier limit")]
+    InvalidLoyaltySchedule,
+    #[msg("Stake history no longer reaches back to this time")]
+    StakeHistoryPruned,
 }
 
 // ===========================================
 // ============ EVENT DEFINITIONS =============
 // ===========================================
 
+// Attached to every state changing event. Indexers can detect missed events by gaps in the sequence.
+#[derive(AnchorSerialize, AnchorDeserialize, Clone, Debug)]
+pub struct EventMetadata {
+    pub sequence: u64,
+    pub timestamp: i64,
+    pub slot: u64,
+    // Post-state of the pool
+    pub total_staked: u64,
+    pub total_withdrawal_request_amount: u64,
+    pub reward_per_token_stored_numerator: u128,
+}
+
 #[event]
 #[derive(Debug)]
 pub struct Initialized {
@@ -1030,6 +4200,7 @@ pub struct Initialized {
     pub token_mint: Pubkey,
     pub withdrawal_delay_seconds: u32,
     pub reward_rate_yearly_percentage_numerator: u64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1037,28 +4208,101 @@ pub struct Initialized {
 pub struct RewardsAdded {
     pub administrator: Pubkey,
     pub amount: u64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
 #[derive(Debug)]
 pub struct RewardRatioConfigured {
     pub administrator: Pubkey,
+    pub old_reward_rate_yearly_percentage_numerator: u64,
+    pub old_reward_rate_per_second_per_token_numerator: u128,
     pub new_reward_rate_yearly_percentage_numerator: u64,
-    pub new_reward_rate_per_second_per_token_numerator: u64,
+    pub new_reward_rate_per_second_per_token_numerator: u128,
+    pub effective_time: i64,
+    // Accumulators at the moment of the change
+    pub reward_per_token_stored_numerator: u128,
+    pub total_reward_promised: u64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
 #[derive(Debug)]
 pub struct WithdrawalDelayConfigured {
     pub administrator: Pubkey,
+    pub old_withdrawal_delay_seconds: u32,
     pub new_withdrawal_delay_seconds: u32,
+    pub effective_time: i64,
+    pub reward_per_token_stored_numerator: u128, // Accumulator at the moment of the change
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct RewardsEndTimeConfigured {
+    pub administrator: Pubkey,
+    pub old_rewards_end_time: Option<i64>,
+    pub new_rewards_end_time: Option<i64>,
+    pub effective_time: i64,
+    // Accumulators at the moment of the change
+    pub reward_per_token_stored_numerator: u128,
+    pub total_reward_promised: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct SolvencyModeConfigured {
+    pub administrator: Pubkey,
+    pub old_enabled: bool,
+    pub new_enabled: bool,
+    pub effective_time: i64,
+    // Accumulators at the moment of the change
+    pub reward_per_token_stored_numerator: u128,
+    pub total_reward_promised: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct PartialRewardPayoutConfigured {
+    pub administrator: Pubkey,
+    pub enabled: bool,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct VoterWeightAgeBonusConfigured {
+    pub administrator: Pubkey,
+    pub age_bonus_numerator: u64,
+    pub saturation_seconds: u32,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct LoyaltyScheduleConfigured {
+    pub administrator: Pubkey,
+    pub old_loyalty_tiers: Vec<LoyaltyTier>,
+    pub new_loyalty_tiers: Vec<LoyaltyTier>,
+    pub loyalty_schedule_epoch: u64,
+    pub effective_time: i64,
+    // Accumulators at the moment of the change
+    pub loyalty_reward_per_token_stored_numerator: [u128; MAX_LOYALTY_TIERS],
+    pub total_reward_promised: u64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
 #[derive(Debug)]
 pub struct OwnershipTransferInitiated {
     pub current_administrator: Pubkey,
+    pub old_pending_administrator: Option<Pubkey>,
     pub new_administrator: Pubkey,
+    pub effective_time: i64,
+    pub reward_per_token_stored_numerator: u128, // Accumulator at the moment of the change
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1066,6 +4310,42 @@ pub struct OwnershipTransferInitiated {
 pub struct OwnershipTransferFinalized {
     pub old_administrator: Pubkey,
     pub new_administrator: Pubkey,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct RewardStreamConfigured {
+    pub administrator: Pubkey,
+    pub treasury_token_account: Pubkey,
+    pub rate_per_second: u64,
+    pub cap: u64,
+    pub total_pulled: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct StatsMigrated {
+    pub administrator: Pubkey,
+    pub args: StatsMigrationArgs,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct SettingsMigrated {
+    pub administrator: Pubkey,
+    pub reward_rate_per_second_per_token_numerator: u128,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct UserInfoMigrated {
+    pub user: Pubkey,
+    pub payer: Pubkey,
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1074,6 +4354,7 @@ pub struct Staked {
     pub user: Pubkey,
     pub amount: u64,
     pub total_user_staked: u64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1084,7 +4365,8 @@ pub struct WithdrawalRequested {
     pub total_token_amount: u64, // Total stake tokens in withdrawal request
     pub added_reward_amount: u64, // Rewards just added to withdrawal request
     pub total_reward_amount: u64, // Total rewards in withdrawal request
-    pub withdrawal_request_time: u32,
+    pub withdrawal_request_time: i64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1093,6 +4375,8 @@ pub struct Withdrawn {
     pub user: Pubkey,
     pub token_amount: u64,
     pub reward_amount: u64,
+    pub unpaid_reward_amount: u64, // Recorded as a reward debt, if the pool was short
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1101,6 +4385,52 @@ pub struct WithdrawnAndForfeitedRewards {
     pub user: Pubkey,
     pub token_amount: u64,
     pub forfeited_reward_amount: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct RewardDebtClaimed {
+    pub user: Pubkey,
+    pub amount: u64,
+    pub remaining_reward_debt_amount: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct RewardsDonated {
+    pub donor: Pubkey,
+    pub amount: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct RewardStreamPulled {
+    pub amount: u64,
+    pub total_pulled: u64,
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct VoterWeightRecordUpdated {
+    pub realm: Pubkey,
+    pub user: Pubkey,
+    pub voter_weight: u64,
+    pub voter_weight_expiry: u64, // Slot
+    pub metadata: EventMetadata,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct LoyaltyTierUpdated {
+    pub user: Pubkey,
+    pub old_loyalty_tier: Option<u8>,
+    pub new_loyalty_tier: Option<u8>,
+    pub captured_reward: u64,
+    pub metadata: EventMetadata,
 }
 
 #[event]
@@ -1109,6 +4439,7 @@ pub struct CurrentRewardsViewed {
     pub user: Pubkey,
     pub captured_reward: u64,
     pub uncaptured_reward: u64,
+    pub withdrawal_request_reward_amount: u64,
     pub total_reward: u64,
 }
 
@@ -1124,3 +4455,716 @@ pub struct RewardRunwayViewed {
     pub available_rewards: u64,
     pub runway_seconds: u64,
 }
+
+#[event]
+#[derive(Debug)]
+pub struct PendingWithdrawalsViewed {
+    pub total_withdrawal_request_amount: u64,
+    pub total_withdrawal_request_reward_amount: u64,
+    pub total_reward_debt: u64,
+    pub protocol_token_account_amount: u64,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct PositionViewed {
+    pub position: PositionView,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct PoolViewed {
+    pub pool: PoolView,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct ProjectedRewardsViewed {
+    pub projection: ProjectedRewardsView,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct SolvencyViewed {
+    pub solvency: SolvencyView,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct PositionsViewed {
+    pub positions: PositionsView,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct RewardRateViewed {
+    pub rate: RewardRateView,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct StakedAtTimeViewed {
+    pub owner: Pubkey,
+    pub timestamp: i64,
+    pub amount: u64,
+}
+
+#[event]
+#[derive(Debug)]
+pub struct TotalStakedAtTimeViewed {
+    pub timestamp: i64,
+    pub amount: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_account_info<R>(
+        data: &mut [u8],
+        f: impl for<'a> FnOnce(&'a AccountInfo<'a>) -> R,
+    ) -> R {
+        let key = Pubkey::new_unique();
+        let owner = crate::ID;
+        let mut lamports = 0;
+        let account = AccountInfo::new(&key, false, true, &mut lamports, data, &owner, false, 0);
+        f(&account)
+    }
+
+    // Byte layouts written by v1.0.0, built field by field
+    fn settings_v1_data(administrator: Pubkey, pending_administrator: Option<Pubkey>) -> Vec<u8> {
+        let mut data = Settings::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(administrator.as_ref());
+        match pending_administrator {
+            Some(pending_administrator) => {
+                data.push(1);
+                data.extend_from_slice(pending_administrator.as_ref());
+            }
+            // Anchor allocates the space of the largest variant
+            None => data.extend_from_slice(&[0; 1 + 32]),
+        }
+        data.extend_from_slice(Pubkey::new_from_array([7; 32]).as_ref());
+        data.extend_from_slice(&432_000u32.to_le_bytes());
+        data.extend_from_slice(&2_536u64.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn detects_v1_settings_by_size() {
+        let administrator = Pubkey::new_unique();
+        let pending_administrator = Pubkey::new_unique();
+        let mut data = settings_v1_data(administrator, Some(pending_administrator));
+        assert_eq!(data.len(), 8 + SettingsV1::SPACE);
+
+        with_account_info(&mut data, |account| {
+            assert_eq!(
+                read_account_version::<Settings>(account, SettingsV1::SPACE).unwrap(),
+                1
+            );
+            assert_eq!(read_administrator(account).unwrap(), administrator);
+
+            let settings_v1 =
+                SettingsV1::deserialize(&mut &account.try_borrow_data().unwrap()[8..]).unwrap();
+            assert_eq!(settings_v1.administrator, administrator);
+            assert_eq!(
+                settings_v1.pending_administrator,
+                Some(pending_administrator)
+            );
+            assert_eq!(settings_v1.token_mint, Pubkey::new_from_array([7; 32]));
+            assert_eq!(settings_v1.withdrawal_delay_seconds, 432_000);
+            assert_eq!(
+                scale_v1_reward_numerator(settings_v1.reward_rate_per_second_per_token_numerator),
+                2_536 * 1_000_000_000_000_000
+            );
+        });
+    }
+
+    #[test]
+    fn reads_the_administrator_of_current_settings() {
+        let administrator = Pubkey::new_unique();
+        let settings = Settings {
+            version: Settings::VERSION,
+            administrator,
+            pending_administrator: None,
+            token_mint: Pubkey::new_unique(),
+            withdrawal_delay_seconds: 0,
+            reward_rate_per_second_per_token_numerator: 0,
+            rewards_end_time: None,
+            solvency_mode_enabled: false,
+            partial_reward_payout_enabled: false,
+            voter_weight_age_bonus_numerator: 0,
+            voter_weight_age_bonus_saturation_seconds: 0,
+            loyalty_tiers: Vec::new(),
+            previous_loyalty_tiers: Vec::new(),
+        };
+        let mut data = vec![0; 8 + Settings::INIT_SPACE];
+        settings.try_serialize(&mut &mut data[..]).unwrap();
+
+        with_account_info(&mut data, |account| {
+            assert_eq!(
+                read_account_version::<Settings>(account, SettingsV1::SPACE).unwrap(),
+                Settings::VERSION
+            );
+            assert_eq!(read_administrator(account).unwrap(), administrator);
+        });
+    }
+
+    #[test]
+    fn rejects_other_accounts() {
+        let mut data = settings_v1_data(Pubkey::new_unique(), None);
+        data[..8].copy_from_slice(Stats::DISCRIMINATOR);
+
+        with_account_info(&mut data, |account| {
+            assert!(read_account_version::<Settings>(account, SettingsV1::SPACE).is_err());
+            assert!(read_administrator(account).is_err());
+        });
+    }
+
+    #[test]
+    fn decodes_v1_stats() {
+        let mut data = Stats::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(&5u64.to_le_bytes());
+        data.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        data.extend_from_slice(&300u64.to_le_bytes());
+        data.extend_from_slice(&20u64.to_le_bytes());
+        data.extend_from_slice(&1_000u64.to_le_bytes());
+        assert_eq!(data.len(), 8 + StatsV1::SPACE);
+
+        with_account_info(&mut data, |account| {
+            assert_eq!(
+                read_account_version::<Stats>(account, StatsV1::SPACE).unwrap(),
+                1
+            );
+            let stats_v1 =
+                StatsV1::deserialize(&mut &account.try_borrow_data().unwrap()[8..]).unwrap();
+            assert_eq!(
+                scale_v1_reward_numerator(stats_v1.reward_per_token_stored_numerator),
+                5 * 1_000_000_000_000_000
+            );
+            assert_eq!(stats_v1.last_update_time, 1_700_000_000);
+            assert_eq!(stats_v1.total_staked, 300);
+            assert_eq!(stats_v1.total_reward_promised, 20);
+            assert_eq!(stats_v1.total_reward_provided, 1_000);
+        });
+    }
+
+    #[test]
+    fn decodes_v1_user_info() {
+        let user = Pubkey::new_unique();
+        let mut data = UserInfo::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(user.as_ref());
+        data.extend_from_slice(&100u64.to_le_bytes());
+        data.extend_from_slice(&1_700_000_000u32.to_le_bytes());
+        data.extend_from_slice(&3u64.to_le_bytes());
+        data.extend_from_slice(&4u64.to_le_bytes());
+        data.extend_from_slice(&1_700_000_100u32.to_le_bytes());
+        data.extend_from_slice(&50u64.to_le_bytes());
+        data.extend_from_slice(&6u64.to_le_bytes());
+        assert_eq!(data.len(), 8 + UserInfoV1::SPACE);
+
+        with_account_info(&mut data, |account| {
+            assert_eq!(
+                read_account_version::<UserInfo>(account, UserInfoV1::SPACE).unwrap(),
+                1
+            );
+            let user_info_v1 =
+                UserInfoV1::deserialize(&mut &account.try_borrow_data().unwrap()[8..]).unwrap();
+            assert_eq!(user_info_v1.user, user);
+            assert_eq!(user_info_v1.stake_amount, 100);
+            assert_eq!(user_info_v1.staked_at, 1_700_000_000);
+            assert_eq!(user_info_v1.reward_per_token_paid_numerator, 3);
+            assert_eq!(user_info_v1.captured_reward, 4);
+            assert_eq!(user_info_v1.withdrawal_request_time, 1_700_000_100);
+            assert_eq!(user_info_v1.withdrawal_request_amount, 50);
+            assert_eq!(user_info_v1.withdrawal_request_reward_amount, 6);
+        });
+    }
+
+    fn position(stake_amount: u64, staked_at: i64) -> UserInfo {
+        UserInfo {
+            version: UserInfo::VERSION,
+            user: Pubkey::new_unique(),
+            stake_amount,
+            staked_at,
+            reward_per_token_paid_numerator: 0,
+            captured_reward: 0,
+            withdrawal_request_time: 0,
+            withdrawal_request_amount: 0,
+            withdrawal_request_reward_amount: 0,
+            reward_debt_amount: 0,
+            lifetime_staked: stake_amount,
+            lifetime_withdrawn: 0,
+            lifetime_rewards_paid: 0,
+            lifetime_rewards_forfeited: 0,
+            loyalty_tier: None,
+            loyalty_reward_per_token_paid_numerator: 0,
+            loyalty_schedule_epoch: 0,
+            bump: 0,
+        }
+    }
+
+    #[test]
+    fn weights_the_position_age_by_the_added_amount() {
+        let user_info = position(100, 1_000);
+
+        assert_eq!(
+            calculate_weighted_staked_at(&user_info, 100, 2_000).unwrap(),
+            1_500
+        );
+        assert_eq!(
+            calculate_weighted_staked_at(&user_info, 300, 2_000).unwrap(),
+            1_750
+        );
+        // Rounded towards the current time
+        assert_eq!(
+            calculate_weighted_staked_at(&user_info, 200, 1_001).unwrap(),
+            1_001
+        );
+    }
+
+    #[test]
+    fn top_ups_do_not_inherit_the_voter_weight_age_bonus() {
+        let settings = Settings {
+            version: Settings::VERSION,
+            administrator: Pubkey::new_unique(),
+            pending_administrator: None,
+            token_mint: Pubkey::new_unique(),
+            withdrawal_delay_seconds: 0,
+            reward_rate_per_second_per_token_numerator: 0,
+            rewards_end_time: None,
+            solvency_mode_enabled: false,
+            partial_reward_payout_enabled: false,
+            voter_weight_age_bonus_numerator: PRECISION,
+            voter_weight_age_bonus_saturation_seconds: 1_000,
+            loyalty_tiers: Vec::new(),
+            previous_loyalty_tiers: Vec::new(),
+        };
+        let mut user_info = position(100, 1_000);
+        assert_eq!(
+            calculate_voter_weight(&settings, &user_info, 2_000).unwrap(),
+            200
+        );
+
+        // The aged tokens keep their bonus, the added ones start without
+        user_info.staked_at = calculate_weighted_staked_at(&user_info, 100, 2_000).unwrap();
+        user_info.stake_amount += 100;
+        assert_eq!(
+            calculate_voter_weight(&settings, &user_info, 2_000).unwrap(),
+            300
+        );
+    }
+
+    // Ring of checkpoints at the given physical positions
+    fn stake_history_data(
+        total_checkpoints: u64,
+        oldest_checkpoint_position: u32,
+        checkpoints: &[(i64, u64)],
+    ) -> Vec<u8> {
+        let mut data = Vec::new();
+        StakeHistory {
+            total_checkpoints,
+            oldest_checkpoint_position,
+        }
+        .try_serialize(&mut data)
+        .unwrap();
+        for &(timestamp, amount) in checkpoints {
+            StakeCheckpoint {
+                timestamp,
+                slot: timestamp as u64,
+                amount,
+            }
+            .serialize(&mut data)
+            .unwrap();
+        }
+        assert_eq!(data.len(), StakeHistory::space(checkpoints.len()));
+        data
+    }
+
+    fn stake_checkpoint_timestamps(stake_history: &Account<StakeHistory>) -> Vec<i64> {
+        (0..count_stake_checkpoints(&stake_history.to_account_info()))
+            .map(|index| {
+                read_stake_checkpoint(stake_history, index)
+                    .unwrap()
+                    .timestamp
+            })
+            .collect()
+    }
+
+    #[test]
+    fn looks_up_a_stake_history_before_it_wraps() {
+        let mut data = stake_history_data(3, 0, &[(30, 1), (40, 2), (50, 3)]);
+        with_account_info(&mut data, |account| {
+            let stake_history = Account::<StakeHistory>::try_from(account).unwrap();
+
+            assert_eq!(find_staked_amount_at_time(&stake_history, 10).unwrap(), 0);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 30).unwrap(), 1);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 45).unwrap(), 2);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 60).unwrap(), 3);
+        });
+    }
+
+    #[test]
+    fn looks_up_a_wrapped_stake_history_from_its_oldest_checkpoint() {
+        // Five checkpoints were recorded, the fourth and the fifth overwrote the first two
+        let mut data = stake_history_data(5, 2, &[(40, 4), (50, 5), (30, 3)]);
+        with_account_info(&mut data, |account| {
+            let stake_history = Account::<StakeHistory>::try_from(account).unwrap();
+
+            assert_eq!(stake_checkpoint_timestamps(&stake_history), [30, 40, 50]);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 35).unwrap(), 3);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 49).unwrap(), 4);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 60).unwrap(), 5);
+            // Balances before the oldest checkpoint kept were overwritten
+            assert!(find_staked_amount_at_time(&stake_history, 29).is_err());
+        });
+    }
+
+    #[test]
+    fn grows_a_wrapped_stake_history_in_place() {
+        // Wrapped ring of three, resized to four
+        let mut data = stake_history_data(5, 2, &[(40, 4), (50, 5), (30, 3), (0, 0)]);
+        with_account_info(&mut data, |account| {
+            let mut stake_history = Account::<StakeHistory>::try_from(account).unwrap();
+            let checkpoint = StakeCheckpoint {
+                timestamp: 60,
+                slot: 60,
+                amount: 6,
+            };
+            insert_stake_checkpoint(&mut stake_history, 3, &checkpoint).unwrap();
+
+            assert_eq!(stake_history.oldest_checkpoint_position, 3);
+            assert_eq!(
+                stake_checkpoint_timestamps(&stake_history),
+                [30, 40, 50, 60]
+            );
+            assert_eq!(find_staked_amount_at_time(&stake_history, 35).unwrap(), 3);
+            assert_eq!(find_staked_amount_at_time(&stake_history, 60).unwrap(), 6);
+        });
+    }
+
+    #[test]
+    fn grows_a_stake_history_before_it_wraps_at_the_end() {
+        let mut data = stake_history_data(2, 0, &[(30, 3), (40, 4), (0, 0)]);
+        with_account_info(&mut data, |account| {
+            let mut stake_history = Account::<StakeHistory>::try_from(account).unwrap();
+            let checkpoint = StakeCheckpoint {
+                timestamp: 50,
+                slot: 50,
+                amount: 5,
+            };
+            insert_stake_checkpoint(&mut stake_history, 2, &checkpoint).unwrap();
+
+            assert_eq!(stake_history.oldest_checkpoint_position, 0);
+            assert_eq!(stake_checkpoint_timestamps(&stake_history), [30, 40, 50]);
+        });
+    }
+
+    const DAY: i64 = SECONDS_PER_DAY as i64;
+    const AMOUNT: u64 = 1_000_000_000_000;
+    const BONUS_1: u64 = 10_000_000_000; // +1% APR
+    const BONUS_2: u64 = 20_000_000_000; // +2% APR
+
+    // Tiers given as (days, yearly bonus)
+    fn loyalty_settings(
+        reward_rate_yearly_percentage_numerator: u64,
+        tiers: &[(u64, u64)],
+    ) -> Settings {
+        Settings {
+            version: Settings::VERSION,
+            administrator: Pubkey::new_unique(),
+            pending_administrator: None,
+            token_mint: Pubkey::new_unique(),
+            withdrawal_delay_seconds: 0,
+            reward_rate_per_second_per_token_numerator:
+                calculate_reward_rate_per_second_per_token_numerator(
+                    reward_rate_yearly_percentage_numerator,
+                ),
+            rewards_end_time: None,
+            solvency_mode_enabled: false,
+            partial_reward_payout_enabled: false,
+            voter_weight_age_bonus_numerator: 0,
+            voter_weight_age_bonus_saturation_seconds: 0,
+            loyalty_tiers: tiers
+                .iter()
+                .map(|&(days, bonus)| LoyaltyTier {
+                    min_staked_seconds: calculate_seconds_from_days(days).unwrap(),
+                    bonus_rate_per_second_per_token_numerator:
+                        calculate_reward_rate_per_second_per_token_numerator(bonus),
+                })
+                .collect(),
+            previous_loyalty_tiers: Vec::new(),
+        }
+    }
+
+    fn pool_stats(total_staked: u64, last_update_time: i64) -> Stats {
+        Stats {
+            version: Stats::VERSION,
+            reward_per_token_stored_numerator: 0,
+            last_update_time,
+            total_staked,
+            total_reward_promised: 0,
+            total_reward_provided: 0,
+            total_reward_debt: 0,
+            total_reward_forfeited: 0,
+            total_withdrawal_request_amount: 0,
+            total_withdrawal_request_reward_amount: 0,
+            active_stakers: 1,
+            total_positions_opened: 1,
+            lifetime_staked: total_staked,
+            lifetime_withdrawn: 0,
+            lifetime_rewards_paid: 0,
+            event_sequence: 0,
+            loyalty_tier_staked: [0; MAX_LOYALTY_TIERS],
+            loyalty_reward_per_token_stored_numerator: [0; MAX_LOYALTY_TIERS],
+            loyalty_schedule_epoch: 1,
+            loyalty_schedule_start_time: 0,
+            previous_loyalty_reward_per_token_stored_numerator: [0; MAX_LOYALTY_TIERS],
+        }
+    }
+
+    // Reward of AMOUNT tokens at the yearly rate, over the given seconds
+    fn reward_over(yearly_percentage_numerator: u64, seconds: i64) -> u64 {
+        calculate_reward_amount(
+            AMOUNT,
+            calculate_reward_rate_per_second_per_token_numerator(yearly_percentage_numerator)
+                * seconds as u128,
+        )
+        .unwrap()
+    }
+
+    fn advance(
+        settings: &Settings,
+        stats: &mut Stats,
+        user_info: &mut UserInfo,
+        current_time: i64,
+    ) {
+        update_accumulators_to(settings, stats, current_time).unwrap();
+        capture_accrued_rewards(settings, stats, user_info).unwrap();
+        update_position_loyalty_tier(settings, stats, user_info, user_info.stake_amount).unwrap();
+    }
+
+    #[test]
+    fn assigns_the_last_loyalty_tier_reached() {
+        let settings = loyalty_settings(0, &[(90, BONUS_1), (180, BONUS_2)]);
+        let user_info = position(AMOUNT, 0);
+
+        assert_eq!(
+            calculate_loyalty_tier(&settings, &user_info, 90 * DAY - 1),
+            None
+        );
+        assert_eq!(
+            calculate_loyalty_tier(&settings, &user_info, 90 * DAY),
+            Some(0)
+        );
+        assert_eq!(
+            calculate_loyalty_tier(&settings, &user_info, 180 * DAY - 1),
+            Some(0)
+        );
+        assert_eq!(
+            calculate_loyalty_tier(&settings, &user_info, 180 * DAY),
+            Some(1)
+        );
+        assert_eq!(
+            calculate_loyalty_tier(&settings, &user_info, 1_000 * DAY),
+            Some(1)
+        );
+        // Positions without a stake earn no bonus
+        assert_eq!(
+            calculate_loyalty_tier(&settings, &position(0, 0), 1_000 * DAY),
+            None
+        );
+    }
+
+    #[test]
+    fn splits_the_loyalty_bonus_at_the_threshold_time() {
+        let settings = loyalty_settings(0, &[(90, BONUS_1), (180, BONUS_2)]);
+        let mut stats = pool_stats(AMOUNT, 90 * DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+        assert_eq!(user_info.loyalty_tier, Some(0));
+        assert_eq!(stats.loyalty_tier_staked[0], AMOUNT);
+
+        // Captured long after the age reached the second tier, which pays from the threshold on
+        let expected_reward = reward_over(BONUS_1, 100 * DAY)
+            + calculate_reward_amount(
+                AMOUNT,
+                (calculate_reward_rate_per_second_per_token_numerator(BONUS_2)
+                    - calculate_reward_rate_per_second_per_token_numerator(BONUS_1))
+                    * (10 * DAY) as u128,
+            )
+            .unwrap();
+        assert_eq!(
+            calculate_uncaptured_loyalty_reward(&settings, &stats, &user_info, 190 * DAY).unwrap(),
+            expected_reward
+        );
+        advance(&settings, &mut stats, &mut user_info, 190 * DAY);
+        assert_eq!(user_info.captured_reward, expected_reward);
+        assert_eq!(user_info.loyalty_tier, Some(1));
+        assert_eq!(stats.loyalty_tier_staked[..2], [0, AMOUNT]);
+        // The second tier was promised by the capture, the first one as it accrued
+        assert_eq!(stats.total_reward_promised, expected_reward);
+    }
+
+    #[test]
+    fn takes_off_the_bonus_of_a_lower_paying_tier_reached() {
+        let settings = loyalty_settings(0, &[(90, BONUS_2), (180, BONUS_1)]);
+        let mut stats = pool_stats(AMOUNT, 90 * DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+
+        advance(&settings, &mut stats, &mut user_info, 190 * DAY);
+        let promised_reward = reward_over(BONUS_2, 100 * DAY);
+        let lost_reward = calculate_reward_amount(
+            AMOUNT,
+            (calculate_reward_rate_per_second_per_token_numerator(BONUS_2)
+                - calculate_reward_rate_per_second_per_token_numerator(BONUS_1))
+                * (10 * DAY) as u128,
+        )
+        .unwrap();
+        assert_eq!(user_info.captured_reward, promised_reward - lost_reward);
+        // Promised by the first tier, but not earned
+        assert_eq!(stats.total_reward_promised, promised_reward);
+        assert_eq!(stats.total_reward_forfeited, lost_reward);
+    }
+
+    #[test]
+    fn promises_the_uncaptured_loyalty_bonus() {
+        let settings = loyalty_settings(80_000_000_000, &[(90, BONUS_1)]);
+        let mut stats = pool_stats(AMOUNT, 90 * DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+
+        let current_time = 100 * DAY;
+        let uncaptured_loyalty_reward =
+            calculate_uncaptured_loyalty_reward(&settings, &stats, &user_info, current_time)
+                .unwrap();
+        assert_eq!(uncaptured_loyalty_reward, reward_over(BONUS_1, 10 * DAY));
+        assert_eq!(
+            calculate_total_promised_rewards(&settings, &stats, current_time).unwrap(),
+            reward_over(80_000_000_000, 10 * DAY) + uncaptured_loyalty_reward
+        );
+    }
+
+    #[test]
+    fn caps_the_loyalty_bonus_by_the_rewards_left() {
+        let mut settings = loyalty_settings(BONUS_1, &[(1, BONUS_1)]);
+        settings.solvency_mode_enabled = true;
+        let mut stats = pool_stats(AMOUNT, DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+
+        // The base rewards take their full share, the bonus what is left
+        let base_reward = reward_over(BONUS_1, 10);
+        stats.total_reward_provided = base_reward + base_reward / 4;
+        update_accumulators_to(&settings, &mut stats, DAY + 10).unwrap();
+        assert_eq!(stats.total_reward_promised, stats.total_reward_provided);
+
+        // Accrual halts, nothing accrues beyond what was promised
+        let loyalty_reward_per_token_stored_numerator =
+            stats.loyalty_reward_per_token_stored_numerator[0];
+        update_accumulators_to(&settings, &mut stats, DAY + 20).unwrap();
+        assert_eq!(
+            stats.loyalty_reward_per_token_stored_numerator[0],
+            loyalty_reward_per_token_stored_numerator
+        );
+
+        capture_accrued_rewards(&settings, &mut stats, &mut user_info).unwrap();
+        assert_eq!(user_info.captured_reward, stats.total_reward_provided);
+    }
+
+    #[test]
+    fn caps_the_base_rewards_before_the_loyalty_bonus() {
+        let mut settings = loyalty_settings(BONUS_1, &[(1, BONUS_1)]);
+        settings.solvency_mode_enabled = true;
+        let mut stats = pool_stats(AMOUNT, DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+
+        stats.total_reward_provided = reward_over(BONUS_1, 10) / 2;
+        update_accumulators_to(&settings, &mut stats, DAY + 10).unwrap();
+        assert_eq!(stats.total_reward_promised, stats.total_reward_provided);
+        assert_eq!(stats.loyalty_reward_per_token_stored_numerator[0], 0);
+    }
+
+    #[test]
+    fn empties_the_loyalty_tiers_on_a_schedule_change() {
+        let mut settings = loyalty_settings(0, &[(90, BONUS_1)]);
+        let mut stats = pool_stats(AMOUNT, 90 * DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+
+        // Settled under the old schedule first, as configure_loyalty_schedule does
+        update_accumulators_to(&settings, &mut stats, 100 * DAY).unwrap();
+        let new_loyalty_tiers = loyalty_settings(0, &[(365, BONUS_2)]).loyalty_tiers;
+        replace_loyalty_schedule(&mut settings, &mut stats, new_loyalty_tiers).unwrap();
+        assert_eq!(stats.loyalty_tier_staked, [0; MAX_LOYALTY_TIERS]);
+
+        // The old membership earns nothing under the new schedule, but keeps the bonus settled before the change
+        update_accumulators_to(&settings, &mut stats, 110 * DAY).unwrap();
+        assert_eq!(
+            calculate_uncaptured_loyalty_reward(&settings, &stats, &user_info, 110 * DAY).unwrap(),
+            reward_over(BONUS_1, 10 * DAY)
+        );
+        advance(&settings, &mut stats, &mut user_info, 110 * DAY);
+        assert_eq!(user_info.captured_reward, reward_over(BONUS_1, 10 * DAY));
+        assert_eq!(stats.total_reward_promised, reward_over(BONUS_1, 10 * DAY));
+
+        // Too young for the new tier
+        assert_eq!(user_info.loyalty_tier, None);
+        assert_eq!(stats.loyalty_tier_staked, [0; MAX_LOYALTY_TIERS]);
+        advance(&settings, &mut stats, &mut user_info, 365 * DAY);
+        assert_eq!(user_info.captured_reward, reward_over(BONUS_1, 10 * DAY));
+        assert_eq!(user_info.loyalty_tier, Some(0));
+    }
+
+    #[test]
+    fn pays_no_bonus_to_positions_of_older_schedules() {
+        let mut settings = loyalty_settings(0, &[(90, BONUS_1)]);
+        let mut stats = pool_stats(AMOUNT, 90 * DAY);
+        let mut user_info = position(AMOUNT, 0);
+        update_position_loyalty_tier(&settings, &mut stats, &mut user_info, AMOUNT).unwrap();
+
+        let new_loyalty_tiers = loyalty_settings(0, &[(90, BONUS_2)]).loyalty_tiers;
+        replace_loyalty_schedule(&mut settings, &mut stats, new_loyalty_tiers).unwrap();
+        let new_loyalty_tiers = loyalty_settings(0, &[(90, BONUS_1)]).loyalty_tiers;
+        replace_loyalty_schedule(&mut settings, &mut stats, new_loyalty_tiers).unwrap();
+
+        update_accumulators_to(&settings, &mut stats, 100 * DAY).unwrap();
+        assert_eq!(
+            calculate_uncaptured_loyalty_reward(&settings, &stats, &user_info, 100 * DAY).unwrap(),
+            0
+        );
+        assert_eq!(
+            calculate_effective_reward_rate_yearly_percentage_numerator(
+                &settings,
+                &stats,
+                calculate_position_loyalty_tier(&stats, &user_info),
+                100 * DAY
+            )
+            .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn includes_the_loyalty_bonus_in_the_effective_rate() {
+        let settings = loyalty_settings(80_000_000_000, &[(90, BONUS_1), (180, BONUS_2)]);
+        let stats = pool_stats(AMOUNT, 0);
+
+        assert_eq!(
+            calculate_effective_reward_rate_yearly_percentage_numerator(&settings, &stats, None, 0)
+                .unwrap(),
+            80_000_000_000
+        );
+        assert_eq!(
+            calculate_effective_reward_rate_yearly_percentage_numerator(
+                &settings,
+                &stats,
+                Some(1),
+                0
+            )
+            .unwrap(),
+            80_000_000_000 + BONUS_2
+        );
+    }
+}
@@ -75,3 +75,74 @@ export async function waitForTransaction(
   }
   return txinfo;
 }
+
+const BPF_LOADER_UPGRADEABLE_PROGRAM_ID = new web3.PublicKey(
+  "BPFLoaderUpgradeab1e11111111111111111111111"
+);
+
+// Transfers the upgrade authority from the provider wallet
+export async function setUpgradeAuthority(
+  provider: anchor.AnchorProvider,
+  programId: web3.PublicKey,
+  newAuthority: web3.PublicKey
+) {
+  const [programData] = web3.PublicKey.findProgramAddressSync(
+    [programId.toBuffer()],
+    BPF_LOADER_UPGRADEABLE_PROGRAM_ID
+  );
+  const instruction = new web3.TransactionInstruction({
+    programId: BPF_LOADER_UPGRADEABLE_PROGRAM_ID,
+    keys: [
+      { pubkey: programData, isSigner: false, isWritable: true },
+      { pubkey: provider.wallet.publicKey, isSigner: true, isWritable: false },
+      { pubkey: newAuthority, isSigner: false, isWritable: false },
+    ],
+    // UpgradeableLoaderInstruction::SetAuthority
+    data: Buffer.from([4, 0, 0, 0]),
+  });
+  await provider.sendAndConfirm(new web3.Transaction().add(instruction));
+}
+
+// Anchor's EVENT_IX_TAG, which prefixes the data of the self-invocations carrying the events
+const EVENT_IX_TAG = Buffer.from([
+  0xe4, 0x45, 0xa5, 0x2e, 0x51, 0xcb, 0x9a, 0x1d,
+]);
+
+// Decodes an event emitted via event-CPI from the data of an inner instruction
+export function decodeCpiEvent(
+  program: anchor.Program<any>,
+  instructionData: string
+): anchor.Event | null {
+  const data = Buffer.from(anchor.utils.bytes.bs58.decode(instructionData));
+  if (!data.subarray(0, 8).equals(EVENT_IX_TAG)) {
+    return null;
+  }
+  return program.coder.events.decode(
+    anchor.utils.bytes.base64.encode(data.subarray(8))
+  );
+}
+
+// Collects the events emitted via event-CPI by the program in a transaction
+export function parseCpiEvents(
+  program: anchor.Program<any>,
+  txinfo: anchor.web3.VersionedTransactionResponse
+): anchor.Event[] {
+  const accountKeys = txinfo.transaction.message.getAccountKeys({
+    accountKeysFromLookups: txinfo.meta.loadedAddresses,
+  });
+
+  const events: anchor.Event[] = [];
+  for (const innerInstructions of txinfo.meta.innerInstructions ?? []) {
+    for (const instruction of innerInstructions.instructions) {
+      const programId = accountKeys.get(instruction.programIdIndex);
+      if (!programId.equals(program.programId)) {
+        continue;
+      }
+      const event = decodeCpiEvent(program, instruction.data);
+      if (event) {
+        events.push(event);
+      }
+    }
+  }
+  return events;
+}
@@ -31,6 +31,20 @@ export function getStatsPDA(programId: web3.PublicKey) {
   )[0];
 }
 
+export function getRewardStreamPDA(programId: web3.PublicKey) {
+  return web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("reward_stream")],
+    programId
+  )[0];
+}
+
+export function getRateHistoryPDA(programId: web3.PublicKey) {
+  return web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("rate_history")],
+    programId
+  )[0];
+}
+
 export function getUserInfoPDA(
   programId: web3.PublicKey,
   user: web3.PublicKey
@@ -41,6 +55,44 @@ export function getUserInfoPDA(
   )[0];
 }
 
+export function getStakeHistoryPDA(
+  programId: web3.PublicKey,
+  user: web3.PublicKey
+) {
+  return web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("stake_history"), user.toBuffer()],
+    programId
+  )[0];
+}
+
+export function getTotalStakeHistoryPDA(programId: web3.PublicKey) {
+  return web3.PublicKey.findProgramAddressSync(
+    [Buffer.from("total_stake_history")],
+    programId
+  )[0];
+}
+
+// Checkpoints follow the discriminator as raw entries
+export async function fetchStakeCheckpoints(
+  connection: web3.Connection,
+  stakeHistory: web3.PublicKey
+) {
+  const { data } = await connection.getAccountInfo(stakeHistory);
+  // The checkpoints form a ring, the oldest one follows the newest once it wraps
+  const oldest = data.readUInt32LE(16);
+  const count = (data.length - 20) / 24;
+  const checkpoints = [];
+  for (let index = 0; index < count; index++) {
+    const offset = 20 + ((oldest + index) % count) * 24;
+    checkpoints.push({
+      timestamp: new anchor.BN(data.subarray(offset, offset + 8), "le"),
+      slot: new anchor.BN(data.subarray(offset + 8, offset + 16), "le"),
+      amount: new anchor.BN(data.subarray(offset + 16, offset + 24), "le"),
+    });
+  }
+  return checkpoints;
+}
+
 export async function takeSnapshot(
   provider: anchor.AnchorProvider,
   program: anchor.Program<SplStakingLocked>,
@@ -53,28 +105,19 @@ export async function takeSnapshot(
 
   transaction.add(
     await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user1.user.publicKey,
-      })
+      .viewCurrentRewards(user1.user.publicKey)
       .instruction()
   );
 
   transaction.add(
     await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user2.user.publicKey,
-      })
+      .viewCurrentRewards(user2.user.publicKey)
       .instruction()
   );
 
   transaction.add(
     await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user3.user.publicKey,
-      })
+      .viewCurrentRewards(user3.user.publicKey)
       .instruction()
   );
 
@@ -97,7 +140,10 @@ export async function takeSnapshot(
       ? {
           captured_reward: event.data.capturedReward.toNumber(),
           uncaptured_reward: event.data.uncapturedReward.toNumber(),
-          total_reward: event.data.totalReward.toNumber(),
+          // Accrued rewards only, excluding rewards locked in a withdrawal request
+          total_reward:
+            event.data.capturedReward.toNumber() +
+            event.data.uncapturedReward.toNumber(),
         }
       : {
           captured_reward: 0,
@@ -2,17 +2,25 @@ import * as anchor from "@coral-xyz/anchor";
 import { Program } from "@coral-xyz/anchor";
 import { SplStakingLocked } from "../target/types/spl_staking_locked";
 import { PublicKey, Keypair, LAMPORTS_PER_SOL } from "@solana/web3.js";
-import { createMint, getAccount } from "@solana/spl-token";
+import { approve, createMint, getAccount } from "@solana/spl-token";
 import { expect, use } from "chai";
 import {
+  decodeCpiEvent,
   newUserWithSOL,
   newUserWithSOLAndToken,
+  parseCpiEvents,
+  setUpgradeAuthority,
   waitForTransaction,
 } from "../tests-common-functions";
 import {
   getSettingsPDA,
   getStatsPDA,
+  getRewardStreamPDA,
+  getRateHistoryPDA,
   getUserInfoPDA,
+  getStakeHistoryPDA,
+  getTotalStakeHistoryPDA,
+  fetchStakeCheckpoints,
   takeSnapshot,
   Snapshot,
 } from "../tests-specific-functions";
@@ -50,6 +58,7 @@ describe("spl-staking-locked", () => {
   const WITHDRAWAL_DELAY_DAYS = 5;
   const REWARD_RATE_0 = 0;
   const REWARD_RATE_1 = 80_000_000_000; // 8% a year
+  const REWARD_RATE_MAX = "18446744073709551615"; // u64::MAX
 
   const USER1_STAKE_AMOUNT = 100;
   const USER2_STAKE_AMOUNT = 200;
@@ -114,7 +123,39 @@ describe("spl-staking-locked", () => {
       2,
       tokenMint, // Use same token for admin2
       tokenOwner,
-      1000
+      2000 // Rewards, and the treasury of the reward stream
+    );
+  });
+
+  it("Admin cannot initialize pool, without being the upgrade authority", async () => {
+    try {
+      await program.methods
+        .initialize(
+          new anchor.BN(WITHDRAWAL_DELAY_DAYS),
+          new anchor.BN(REWARD_RATE_0)
+        )
+        .accounts({
+          administrator: admin.user.publicKey,
+          tokenMint: tokenMint,
+        })
+        .signers([admin.user])
+        .rpc();
+      expect.fail("Only the upgrade authority should initialize the pool");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("UnauthorizedInitialization");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("Upgrade authority is transferred to admin", async () => {
+    await setUpgradeAuthority(
+      provider,
+      program.programId,
+      admin.user.publicKey
     );
   });
 
@@ -158,6 +199,7 @@ describe("spl-staking-locked", () => {
     expect(settings.administrator.toString()).to.equal(
       admin.user.publicKey.toString()
     );
+    expect(settings.version).to.equal(2);
     expect(settings.pendingAdministrator).to.be.null;
     expect(settings.tokenMint).to.deep.equal(tokenMint);
     expect(settings.withdrawalDelaySeconds).to.equal(
@@ -170,11 +212,27 @@ describe("spl-staking-locked", () => {
     //   Math.floor(INITIAL_REWARD_RATE / (365 * 24 * 60 * 60)) // Convert annual rate to per second, floored
     // );
 
+    // Verify the rate history starts with the initial rate
+    const rateHistory = await program.account.rateHistory.fetch(
+      getRateHistoryPDA(program.programId)
+    );
+    expect(rateHistory.checkpoints.length).to.equal(1);
+    expect(
+      rateHistory.checkpoints[0].oldRewardRatePerSecondPerTokenNumerator.toNumber()
+    ).to.equal(0);
+    expect(
+      rateHistory.checkpoints[0].newRewardRatePerSecondPerTokenNumerator.toNumber()
+    ).to.equal(0);
+    expect(
+      rateHistory.checkpoints[0].rewardPerTokenStoredNumerator.toNumber()
+    ).to.equal(0);
+
     // Verify stats state
     const statsPDA = getStatsPDA(program.programId);
     const stats = await program.account.stats.fetch(statsPDA);
+    expect(stats.version).to.equal(2);
     expect(stats.rewardPerTokenStoredNumerator.toNumber()).to.equal(0);
-    expect(stats.lastUpdateTime).to.be.greaterThan(0);
+    expect(stats.lastUpdateTime.toNumber()).to.be.greaterThan(0);
     expect(stats.totalRewardPromised.toNumber()).to.equal(0);
     expect(stats.totalRewardProvided.toNumber()).to.equal(0);
   });
@@ -209,7 +267,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user1.user.publicKey);
@@ -222,13 +280,20 @@ describe("spl-staking-locked", () => {
 
     const userInfoPDA = getUserInfoPDA(program.programId, user1.user.publicKey);
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
+    expect(userInfo.version).to.equal(2);
     expect(userInfo.user.toString()).to.equal(user1.user.publicKey.toString());
+    expect(userInfo.bump).to.equal(
+      PublicKey.findProgramAddressSync(
+        [Buffer.from("user_info"), user1.user.publicKey.toBuffer()],
+        program.programId
+      )[1]
+    );
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER1_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.equal(txinfo.blockTime);
+    expect(userInfo.stakedAt.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -266,7 +331,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user2.user.publicKey);
@@ -283,9 +348,9 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.equal(txinfo.blockTime);
+    expect(userInfo.stakedAt.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -315,10 +380,7 @@ describe("spl-staking-locked", () => {
     expect(
       (
         await program.methods
-          .viewCurrentRewards()
-          .accounts({
-            user: user1.user.publicKey,
-          })
+          .viewCurrentRewards(user1.user.publicKey)
           .view()
       ).toNumber()
     ).to.eq(0);
@@ -327,10 +389,7 @@ describe("spl-staking-locked", () => {
     expect(
       (
         await program.methods
-          .viewCurrentRewards()
-          .accounts({
-            user: user2.user.publicKey,
-          })
+          .viewCurrentRewards(user2.user.publicKey)
           .view()
       ).toNumber()
     ).to.eq(0);
@@ -339,6 +398,12 @@ describe("spl-staking-locked", () => {
   it(`+ User1 stakes additional ${USER1_STAKE_AMOUNT} tokens [total: ${
     USER1_STAKE_AMOUNT * 2
   } | staked: ${USER1_STAKE_AMOUNT * 2}]`, async () => {
+    const stakedAtBefore = (
+      await program.account.userInfo.fetch(
+        getUserInfoPDA(program.programId, user1.user.publicKey)
+      )
+    ).stakedAt.toNumber();
+
     const tx = await program.methods
       .stake(new anchor.BN(USER1_STAKE_AMOUNT * LAMPORTS_PER_SOL))
       .accounts({
@@ -349,7 +414,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user1.user.publicKey);
@@ -366,9 +431,13 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER1_STAKE_AMOUNT * 2 * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.within(1, txinfo.blockTime - 1); // Not zero. Not the current time. Should not be updated on stake extension
+    // The added half of the position starts with no age, so the position is half as old
+    const age = txinfo.blockTime - stakedAtBefore;
+    expect(userInfo.stakedAt.toNumber()).to.equal(
+      txinfo.blockTime - Math.floor(age / 2)
+    );
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -398,10 +467,7 @@ describe("spl-staking-locked", () => {
     expect(
       (
         await program.methods
-          .viewCurrentRewards()
-          .accounts({
-            user: user2.user.publicKey,
-          })
+          .viewCurrentRewards(user2.user.publicKey)
           .view()
       ).toNumber()
     ).to.eq(0);
@@ -418,7 +484,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user4.user.publicKey);
@@ -435,9 +501,9 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER4_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.equal(txinfo.blockTime);
+    expect(userInfo.stakedAt.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -453,9 +519,12 @@ describe("spl-staking-locked", () => {
   });
 
   it("! Configure reward rate to 8% per year. Still no rewards in the protocol", async () => {
-    const expectedRewardRatePerSecondPerTokenNumerator = Math.floor(
-      REWARD_RATE_1 / (365 * 24 * 60 * 60)
-    );
+    // Rate is stored with 1e27 precision, while the argument has 1e12 precision
+    const expectedRewardRatePerSecondPerTokenNumerator = new anchor.BN(
+      REWARD_RATE_1
+    )
+      .mul(new anchor.BN(10).pow(new anchor.BN(15)))
+      .div(new anchor.BN(365 * 24 * 60 * 60));
 
     const tx = await program.methods
       .configureRewardRatio(new anchor.BN(REWARD_RATE_1))
@@ -466,7 +535,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("rewardRatioConfigured");
     expect(events[0].data.administrator).to.deep.eq(admin.user.publicKey);
@@ -474,8 +543,15 @@ describe("spl-staking-locked", () => {
       events[0].data.newRewardRateYearlyPercentageNumerator.toNumber()
     ).to.eq(REWARD_RATE_1);
     expect(
-      events[0].data.newRewardRatePerSecondPerTokenNumerator.toNumber()
-    ).to.eq(expectedRewardRatePerSecondPerTokenNumerator);
+      events[0].data.newRewardRatePerSecondPerTokenNumerator.toString()
+    ).to.eq(expectedRewardRatePerSecondPerTokenNumerator.toString());
+    expect(
+      events[0].data.oldRewardRateYearlyPercentageNumerator.toNumber()
+    ).to.eq(0);
+    expect(
+      events[0].data.oldRewardRatePerSecondPerTokenNumerator.toNumber()
+    ).to.eq(0);
+    expect(events[0].data.effectiveTime.toNumber()).to.eq(txinfo.blockTime);
 
     // Store the curent time as the reward start time
     const slot = await provider.connection.getSlot();
@@ -484,8 +560,8 @@ describe("spl-staking-locked", () => {
     // Verify settings state updated
     const settingsPDA = getSettingsPDA(program.programId);
     const settings = await program.account.settings.fetch(settingsPDA);
-    expect(settings.rewardRatePerSecondPerTokenNumerator.toNumber()).to.equal(
-      expectedRewardRatePerSecondPerTokenNumerator
+    expect(settings.rewardRatePerSecondPerTokenNumerator.toString()).to.equal(
+      expectedRewardRatePerSecondPerTokenNumerator.toString()
     );
 
     // Verify stats state updated
@@ -515,10 +591,7 @@ describe("spl-staking-locked", () => {
     expect(
       (
         await program.methods
-          .viewCurrentRewards()
-          .accounts({
-            user: user1.user.publicKey,
-          })
+          .viewCurrentRewards(user1.user.publicKey)
           .view()
       ).toNumber()
     ).gt(0);
@@ -526,10 +599,7 @@ describe("spl-staking-locked", () => {
     expect(
       (
         await program.methods
-          .viewCurrentRewards()
-          .accounts({
-            user: user2.user.publicKey,
-          })
+          .viewCurrentRewards(user2.user.publicKey)
           .view()
       ).toNumber()
     ).gt(0);
@@ -566,15 +636,9 @@ describe("spl-staking-locked", () => {
   });
 
   it("   Test consistency: both simulations in same transaction context should match", async () => {
-    // Create a single transaction with both instructions
-    const transaction = new anchor.web3.Transaction();
-
-    // Add both instructions to the same transaction
+    // Create both instructions for the same transaction
     const viewCurrentRewardsIx = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user1.user.publicKey,
-      })
+      .viewCurrentRewards(user1.user.publicKey)
       .instruction();
 
     const requestWithdrawalIx = await program.methods
@@ -584,21 +648,42 @@ describe("spl-staking-locked", () => {
       })
       .instruction();
 
-    transaction.add(viewCurrentRewardsIx);
-    transaction.add(requestWithdrawalIx);
+    const message = new anchor.web3.TransactionMessage({
+      payerKey: user1.user.publicKey,
+      recentBlockhash: (await provider.connection.getLatestBlockhash())
+        .blockhash,
+      instructions: [viewCurrentRewardsIx, requestWithdrawalIx],
+    }).compileToV0Message();
 
     // Simulate the entire transaction at once
-    const result = await provider.connection.simulateTransaction(transaction, [
-      user1.user,
-    ]);
-
-    // Parse events from the simulation logs
-    const events = [...eventParser.parseLogs(result.value.logs)];
+    const result = await provider.connection.simulateTransaction(
+      new anchor.web3.VersionedTransaction(message),
+      { sigVerify: false, innerInstructions: true }
+    );
+
+    // View events are logged, state changing events are emitted via event-CPI
+    const events = [
+      ...eventParser.parseLogs(result.value.logs),
+      ...result.value.innerInstructions.flatMap((innerInstructions) =>
+        innerInstructions.instructions
+          .filter(
+            (instruction) =>
+              "data" in instruction &&
+              instruction.programId.equals(program.programId)
+          )
+          .map((instruction) =>
+            decodeCpiEvent(
+              program,
+              (instruction as anchor.web3.PartiallyDecodedInstruction).data
+            )
+          )
+      ),
+    ];
 
     // Find the events from each instruction
-    const viewEvent = events.find((e) => e.name === "currentRewardsViewed");
+    const viewEvent = events.find((e) => e?.name === "currentRewardsViewed");
     const withdrawalEvent = events.find(
-      (e) => e.name === "withdrawalRequested"
+      (e) => e?.name === "withdrawalRequested"
     );
 
     expect(viewEvent).to.not.be.undefined;
@@ -638,10 +723,7 @@ describe("spl-staking-locked", () => {
     2 * USER1_STAKE_AMOUNT
   } | staked: 0]`, async () => {
     const expectedReward = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user1.user.publicKey,
-      })
+      .viewCurrentRewards(user1.user.publicKey)
       .view();
     const tx = await program.methods
       .requestWithdrawal()
@@ -652,7 +734,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawalRequested");
     expect(events[0].data.user).to.deep.eq(user1.user.publicKey);
@@ -668,14 +750,14 @@ describe("spl-staking-locked", () => {
     expect(events[0].data.totalRewardAmount.toNumber()).gte(
       expectedReward.toNumber()
     );
-    expect(events[0].data.withdrawalRequestTime).to.eq(txinfo.blockTime);
+    expect(events[0].data.withdrawalRequestTime.toNumber()).to.eq(txinfo.blockTime);
 
     const userInfoPDA = getUserInfoPDA(program.programId, user1.user.publicKey);
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
-    expect(userInfo.stakedAt).to.equal(0);
+    expect(userInfo.stakedAt.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(txinfo.blockTime);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER1_STAKE_AMOUNT * 2 * LAMPORTS_PER_SOL
     );
@@ -688,6 +770,12 @@ describe("spl-staking-locked", () => {
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL +
         USER4_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
+    expect(stats.totalWithdrawalRequestAmount.toNumber()).to.equal(
+      USER1_STAKE_AMOUNT * 2 * LAMPORTS_PER_SOL
+    );
+    expect(stats.totalWithdrawalRequestRewardAmount.toNumber()).to.equal(
+      userInfo.withdrawalRequestRewardAmount.toNumber()
+    );
 
     const userInfoTokenAccount = await anchor.utils.token.associatedAddress({
       mint: tokenMint,
@@ -791,7 +879,7 @@ describe("spl-staking-locked", () => {
       .signers([user2.user])
       .rpc();
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawalRequested");
     user2FirstRequestRewards = events[0].data.addedRewardAmount.toNumber();
@@ -805,9 +893,9 @@ describe("spl-staking-locked", () => {
     const userInfoPDA = getUserInfoPDA(program.programId, user2.user.publicKey);
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
-    expect(userInfo.stakedAt).to.equal(0);
+    expect(userInfo.stakedAt.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(txinfo.blockTime);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
@@ -839,7 +927,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user2.user.publicKey);
@@ -856,9 +944,9 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.equal(txinfo.blockTime);
+    expect(userInfo.stakedAt.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.gt(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.gt(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
@@ -892,23 +980,21 @@ describe("spl-staking-locked", () => {
   });
 
   it("   User1 accumulates nothing while waiting for withdrawal", async () => {
-    expect(
-      (
-        await program.methods
-          .viewCurrentRewards()
-          .accounts({
-            user: user1.user.publicKey,
-          })
-          .view()
-      ).toNumber()
-    ).to.eq(0);
-
     const user1InfoPDA = getUserInfoPDA(
       program.programId,
       user1.user.publicKey
     );
     const user1Info = await program.account.userInfo.fetch(user1InfoPDA);
     expect(user1Info.capturedReward.toNumber()).to.equal(0);
+
+    // Only the rewards locked in the pending withdrawal request are reported
+    expect(
+      (
+        await program.methods
+          .viewCurrentRewards(user1.user.publicKey)
+          .view()
+      ).toNumber()
+    ).to.eq(user1Info.withdrawalRequestRewardAmount.toNumber());
   });
 
   it("Admin can initiate ownership transfer to admin2", async () => {
@@ -921,13 +1007,15 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("ownershipTransferInitiated");
     expect(events[0].data.currentAdministrator).to.deep.eq(
       admin.user.publicKey
     );
     expect(events[0].data.newAdministrator).to.deep.eq(admin2.user.publicKey);
+    expect(events[0].data.oldPendingAdministrator).to.be.null;
+    expect(events[0].data.effectiveTime.toNumber()).to.eq(txinfo.blockTime);
 
     // Verify settings state updated
     const settingsPDA = getSettingsPDA(program.programId);
@@ -967,7 +1055,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("ownershipTransferFinalized");
     expect(events[0].data.oldAdministrator).to.deep.eq(admin.user.publicKey);
@@ -1003,6 +1091,9 @@ describe("spl-staking-locked", () => {
   });
 
   it("Admin2 sets withdrawal delay to 0 days, so that we can test the withdrawal", async () => {
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settingsBefore = await program.account.settings.fetch(settingsPDA);
+
     const tx = await program.methods
       .configureWithdrawalDelay(new anchor.BN(0))
       .accounts({
@@ -1012,14 +1103,17 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawalDelayConfigured");
     expect(events[0].data.administrator).to.deep.eq(admin2.user.publicKey);
     expect(events[0].data.newWithdrawalDelaySeconds).to.eq(0);
+    expect(events[0].data.oldWithdrawalDelaySeconds).to.eq(
+      settingsBefore.withdrawalDelaySeconds
+    );
+    expect(events[0].data.effectiveTime.toNumber()).to.eq(txinfo.blockTime);
 
     // Verify settings state updated
-    const settingsPDA = getSettingsPDA(program.programId);
     const settings = await program.account.settings.fetch(settingsPDA);
     expect(settings.withdrawalDelaySeconds).to.equal(0);
   });
@@ -1046,10 +1140,7 @@ describe("spl-staking-locked", () => {
 
   it("  User4 has some pending rewards", async () => {
     const expectedReward = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user4.user.publicKey,
-      })
+      .viewCurrentRewards(user4.user.publicKey)
       .view();
     expect(expectedReward.toNumber()).to.be.greaterThan(0);
   });
@@ -1064,7 +1155,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawalRequested");
     expect(events[0].data.user).to.deep.eq(user4.user.publicKey);
@@ -1076,14 +1167,14 @@ describe("spl-staking-locked", () => {
     );
     expect(events[0].data.addedRewardAmount.toNumber()).to.be.gt(0);
     expect(events[0].data.totalRewardAmount.toNumber()).to.be.gt(0);
-    expect(events[0].data.withdrawalRequestTime).to.eq(txinfo.blockTime);
+    expect(events[0].data.withdrawalRequestTime.toNumber()).to.eq(txinfo.blockTime);
 
     const userInfoPDA = getUserInfoPDA(program.programId, user4.user.publicKey);
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
-    expect(userInfo.stakedAt).to.equal(0);
+    expect(userInfo.stakedAt.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(txinfo.blockTime);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER4_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
@@ -1116,7 +1207,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawnAndForfeitedRewards");
     expect(events[0].data.user).to.deep.eq(user4.user.publicKey);
@@ -1130,9 +1221,9 @@ describe("spl-staking-locked", () => {
     // Verify user info is cleared
     const userInfo = await program.account.userInfo.fetch(user4InfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
-    expect(userInfo.stakedAt).to.equal(0);
+    expect(userInfo.stakedAt.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -1142,6 +1233,9 @@ describe("spl-staking-locked", () => {
     expect(stats.totalStaked.toNumber()).to.equal(
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
+    expect(stats.totalRewardForfeited.toNumber()).to.equal(
+      forfeited_reward_amount
+    );
 
     // Check token balances - user should have received their withdrawal request tokens
     // Verify no reward tokens were transferred to user4 (they were forfeited)
@@ -1166,7 +1260,7 @@ describe("spl-staking-locked", () => {
       .signers([admin2.user])
       .rpc();
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("rewardsAdded");
     expect(events[0].data.administrator).to.deep.eq(admin2.user.publicKey);
@@ -1199,7 +1293,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawn");
     expect(events[0].data.user).to.deep.eq(user1.user.publicKey);
@@ -1211,9 +1305,9 @@ describe("spl-staking-locked", () => {
     const userInfoPDA = getUserInfoPDA(program.programId, user1.user.publicKey);
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
-    expect(userInfo.stakedAt).to.equal(0);
+    expect(userInfo.stakedAt.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -1282,7 +1376,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user3.user.publicKey);
@@ -1299,9 +1393,9 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER3_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.equal(txinfo.blockTime);
+    expect(userInfo.stakedAt.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -1346,10 +1440,7 @@ describe("spl-staking-locked", () => {
 
   it("   User2 successfully accumulates new rewards while waiting for withdrawal", async () => {
     const expectedReward = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user2.user.publicKey,
-      })
+      .viewCurrentRewards(user2.user.publicKey)
       .view();
     expect(expectedReward.toNumber()).to.be.greaterThan(0);
   });
@@ -1361,10 +1452,7 @@ describe("spl-staking-locked", () => {
 
   it(`- User3 requests withdrawal [total: ${USER3_STAKE_AMOUNT} | staked: 0]`, async () => {
     const expectedReward = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user3.user.publicKey,
-      })
+      .viewCurrentRewards(user3.user.publicKey)
       .view();
     const tx = await program.methods
       .requestWithdrawal()
@@ -1375,7 +1463,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawalRequested");
     expect(events[0].data.user).to.deep.eq(user3.user.publicKey);
@@ -1391,18 +1479,18 @@ describe("spl-staking-locked", () => {
     expect(events[0].data.totalRewardAmount.toNumber()).to.be.gte(
       expectedReward.toNumber()
     );
-    expect(events[0].data.withdrawalRequestTime).to.be.greaterThan(
+    expect(events[0].data.withdrawalRequestTime.toNumber()).to.be.greaterThan(
       rewardStartedAt
     );
     user3FirstRequestRewards = events[0].data.addedRewardAmount.toNumber();
-    user3FirstRequestTimestamp = events[0].data.withdrawalRequestTime;
+    user3FirstRequestTimestamp = events[0].data.withdrawalRequestTime.toNumber();
 
     const userInfoPDA = getUserInfoPDA(program.programId, user3.user.publicKey);
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
-    expect(userInfo.stakedAt).to.equal(0);
+    expect(userInfo.stakedAt.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.be.greaterThan(rewardStartedAt);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.be.greaterThan(rewardStartedAt);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER3_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
@@ -1424,7 +1512,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("staked");
     expect(events[0].data.user).to.deep.eq(user3.user.publicKey);
@@ -1441,9 +1529,9 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER3_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.equal(txinfo.blockTime);
+    expect(userInfo.stakedAt.toNumber()).to.equal(txinfo.blockTime);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.gt(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.gt(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER3_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
@@ -1488,7 +1576,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawn");
     expect(events[0].data.user).to.deep.eq(user2.user.publicKey);
@@ -1502,9 +1590,9 @@ describe("spl-staking-locked", () => {
     expect(userInfo.stakeAmount.toNumber()).to.equal(
       USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
     );
-    expect(userInfo.stakedAt).to.within(1, txinfo.blockTime - 1); // Not zero. Not the current time. Should not be updated on stake extension
+    expect(userInfo.stakedAt.toNumber()).to.within(1, txinfo.blockTime - 1); // Not zero. Not the current time. Should not be updated on stake extension
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.equal(0);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(0);
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).to.equal(0);
 
@@ -1549,7 +1637,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("rewardRatioConfigured");
     expect(events[0].data.administrator).to.deep.eq(admin2.user.publicKey);
@@ -1559,6 +1647,23 @@ describe("spl-staking-locked", () => {
     expect(
       events[0].data.newRewardRatePerSecondPerTokenNumerator.toNumber()
     ).to.eq(0);
+    expect(
+      events[0].data.oldRewardRateYearlyPercentageNumerator.toNumber()
+    ).to.be.greaterThan(0);
+
+    // The accumulators are settled under the old rate at the moment of the change
+    const stats = await program.account.stats.fetch(
+      getStatsPDA(program.programId)
+    );
+    expect(events[0].data.effectiveTime.toString()).to.eq(
+      stats.lastUpdateTime.toString()
+    );
+    expect(events[0].data.rewardPerTokenStoredNumerator.toString()).to.eq(
+      stats.rewardPerTokenStoredNumerator.toString()
+    );
+    expect(events[0].data.totalRewardPromised.toString()).to.eq(
+      stats.totalRewardPromised.toString()
+    );
 
     // Verify settings state updated
     const settingsPDA = getSettingsPDA(program.programId);
@@ -1609,10 +1714,7 @@ describe("spl-staking-locked", () => {
     USER3_STAKE_AMOUNT * 2
   } | staked: 0]`, async () => {
     const expectedReward = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user3.user.publicKey,
-      })
+      .viewCurrentRewards(user3.user.publicKey)
       .view();
     const tx = await program.methods
       .requestWithdrawal()
@@ -1623,7 +1725,7 @@ describe("spl-staking-locked", () => {
       .rpc();
 
     const txinfo = await waitForTransaction(provider.connection, tx);
-    const events = [...eventParser.parseLogs(txinfo.meta.logMessages)];
+    const events = parseCpiEvents(program, txinfo);
     expect(events.length).to.eq(1);
     expect(events[0].name).to.eq("withdrawalRequested");
     expect(events[0].data.user).to.deep.eq(user3.user.publicKey);
@@ -1633,13 +1735,14 @@ describe("spl-staking-locked", () => {
     expect(events[0].data.totalTokenAmount.toNumber()).to.eq(
       USER3_STAKE_AMOUNT * 2 * LAMPORTS_PER_SOL
     );
+    // The view already includes the rewards of the first request
     expect(events[0].data.addedRewardAmount.toNumber()).to.be.gte(
-      expectedReward.toNumber()
+      expectedReward.toNumber() - user3FirstRequestRewards
     );
     expect(events[0].data.totalRewardAmount.toNumber()).to.be.gte(
       expectedReward.toNumber()
     );
-    expect(events[0].data.withdrawalRequestTime).to.be.greaterThan(
+    expect(events[0].data.withdrawalRequestTime.toNumber()).to.be.greaterThan(
       user3FirstRequestTimestamp
     );
     user3SecondRequestRewards = events[0].data.addedRewardAmount.toNumber();
@@ -1648,41 +1751,29 @@ describe("spl-staking-locked", () => {
     const userInfo = await program.account.userInfo.fetch(userInfoPDA);
     expect(userInfo.stakeAmount.toNumber()).to.equal(0);
     expect(userInfo.capturedReward.toNumber()).to.equal(0);
-    expect(userInfo.withdrawalRequestTime).to.be.greaterThan(rewardStartedAt);
+    expect(userInfo.withdrawalRequestTime.toNumber()).to.be.greaterThan(rewardStartedAt);
     expect(userInfo.withdrawalRequestAmount.toNumber()).to.equal(
       USER3_STAKE_AMOUNT * 2 * LAMPORTS_PER_SOL
     );
     expect(userInfo.withdrawalRequestRewardAmount.toNumber()).gte(
-      user3FirstRequestRewards + expectedReward.toNumber()
+      expectedReward.toNumber()
     );
   });
 
   it("   Rewards are not accumulating anymore", async () => {
     const user2RewardsPrior = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user2.user.publicKey,
-      })
+      .viewCurrentRewards(user2.user.publicKey)
       .view();
     const user3RewardsPrior = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user3.user.publicKey,
-      })
+      .viewCurrentRewards(user3.user.publicKey)
       .view();
     await new Promise((resolve) => setTimeout(resolve, 2000));
 
     const user2RewardsAfter = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user2.user.publicKey,
-      })
+      .viewCurrentRewards(user2.user.publicKey)
       .view();
     const user3RewardsAfter = await program.methods
-      .viewCurrentRewards()
-      .accounts({
-        user: user3.user.publicKey,
-      })
+      .viewCurrentRewards(user3.user.publicKey)
       .view();
 
     expect(user2RewardsAfter.toNumber()).to.equal(user2RewardsPrior.toNumber());
@@ -1708,4 +1799,1456 @@ describe("spl-staking-locked", () => {
       }
     }
   });
+
+  it("User1 donates rewards to the contract", async () => {
+    const AMOUNT = 10 * LAMPORTS_PER_SOL;
+    const statsPDA = getStatsPDA(program.programId);
+    const statsBefore = await program.account.stats.fetch(statsPDA);
+
+    const tx = await program.methods
+      .donateRewards(new anchor.BN(AMOUNT))
+      .accounts({
+        donor: user1.user.publicKey,
+        donorTokenAccount: user1.ata,
+      })
+      .signers([user1.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("rewardsDonated");
+    expect(events[0].data.donor).to.deep.eq(user1.user.publicKey);
+    expect(events[0].data.amount.toNumber()).to.eq(AMOUNT);
+
+    const statsAfter = await program.account.stats.fetch(statsPDA);
+    expect(statsAfter.totalRewardProvided.toNumber()).to.equal(
+      statsBefore.totalRewardProvided.toNumber() + AMOUNT
+    );
+  });
+
+  it("   User1 cannot donate 0 tokens", async () => {
+    try {
+      await program.methods
+        .donateRewards(new anchor.BN(0))
+        .accounts({
+          donor: user1.user.publicKey,
+          donorTokenAccount: user1.ata,
+        })
+        .signers([user1.user])
+        .rpc();
+      expect.fail("Donation of 0 tokens should not be allowed");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("InvalidAmount");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Reward stream cannot be pulled before it is configured", async () => {
+    try {
+      await program.methods
+        .pullRewardStream()
+        .accounts({ treasuryTokenAccount: admin2.ata })
+        .rpc();
+      expect.fail("Pulling a missing reward stream should fail");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("AccountNotInitialized");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("Admin2 configures reward stream from his token account", async () => {
+    const RATE = LAMPORTS_PER_SOL; // 1 token per second
+    const CAP = 3 * LAMPORTS_PER_SOL;
+    await approve(
+      provider.connection,
+      admin2.user,
+      admin2.ata,
+      getSettingsPDA(program.programId),
+      admin2.user,
+      CAP
+    );
+
+    const tx = await program.methods
+      .configureRewardStream(new anchor.BN(RATE), new anchor.BN(CAP))
+      .accounts({
+        administrator: admin2.user.publicKey,
+        treasuryTokenAccount: admin2.ata,
+      })
+      .signers([admin2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("rewardStreamConfigured");
+    expect(events[0].data.treasuryTokenAccount).to.deep.eq(admin2.ata);
+    expect(events[0].data.ratePerSecond.toNumber()).to.eq(RATE);
+    expect(events[0].data.cap.toNumber()).to.eq(CAP);
+
+    const rewardStream = await program.account.rewardStream.fetch(
+      getRewardStreamPDA(program.programId)
+    );
+    expect(rewardStream.totalPulled.toNumber()).to.equal(0);
+    expect(rewardStream.lastPullTime.toNumber()).to.be.greaterThan(0);
+  });
+
+  it("   User1 cannot configure reward stream", async () => {
+    try {
+      await program.methods
+        .configureRewardStream(new anchor.BN(1), new anchor.BN(1))
+        .accounts({
+          administrator: user1.user.publicKey,
+          treasuryTokenAccount: user1.ata,
+        })
+        .signers([user1.user])
+        .rpc();
+      expect.fail("User1 should not be able to configure reward stream");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("ConstraintHasOne");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Wait a few seconds", async () => {
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+  });
+
+  it("Anyone can pull the reward stream, up to the cap", async () => {
+    const statsPDA = getStatsPDA(program.programId);
+    const statsBefore = await program.account.stats.fetch(statsPDA);
+
+    const tx = await program.methods
+      .pullRewardStream()
+      .accounts({ treasuryTokenAccount: admin2.ata })
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("rewardStreamPulled");
+    const pulled = events[0].data.amount.toNumber();
+    expect(pulled).to.be.gt(0);
+    expect(pulled).to.be.lte(3 * LAMPORTS_PER_SOL);
+
+    const statsAfter = await program.account.stats.fetch(statsPDA);
+    expect(statsAfter.totalRewardProvided.toNumber()).to.equal(
+      statsBefore.totalRewardProvided.toNumber() + pulled
+    );
+  });
+
+  it("   Wait a few seconds", async () => {
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+  });
+
+  it("   Reward stream stops at the cap", async () => {
+    await program.methods
+      .pullRewardStream()
+      .accounts({ treasuryTokenAccount: admin2.ata })
+      .rpc();
+
+    const rewardStream = await program.account.rewardStream.fetch(
+      getRewardStreamPDA(program.programId)
+    );
+    expect(rewardStream.totalPulled.toNumber()).to.equal(
+      3 * LAMPORTS_PER_SOL
+    );
+
+    try {
+      await program.methods
+        .pullRewardStream()
+        .accounts({ treasuryTokenAccount: admin2.ata })
+        .rpc();
+      expect.fail("Nothing should be left to pull");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("InvalidAmount");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Reward stream carries over what the allowance can not cover", async () => {
+    const RATE = LAMPORTS_PER_SOL; // 1 token per second
+    // Covers a single second of funding
+    await approve(
+      provider.connection,
+      admin2.user,
+      admin2.ata,
+      getSettingsPDA(program.programId),
+      admin2.user,
+      RATE + RATE / 2
+    );
+    await program.methods
+      .configureRewardStream(
+        new anchor.BN(RATE),
+        new anchor.BN(100 * LAMPORTS_PER_SOL)
+      )
+      .accounts({
+        administrator: admin2.user.publicKey,
+        treasuryTokenAccount: admin2.ata,
+      })
+      .signers([admin2.user])
+      .rpc();
+    const configured = await program.account.rewardStream.fetch(
+      getRewardStreamPDA(program.programId)
+    );
+
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+
+    const tx = await program.methods
+      .pullRewardStream()
+      .accounts({ treasuryTokenAccount: admin2.ata })
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events[0].data.amount.toNumber()).to.eq(RATE);
+
+    const limited = await program.account.rewardStream.fetch(
+      getRewardStreamPDA(program.programId)
+    );
+    expect(limited.lastPullTime.toNumber()).to.eq(
+      configured.lastPullTime.toNumber() + 1
+    );
+
+    // The seconds not paid for are pulled, once the allowance is raised
+    await approve(
+      provider.connection,
+      admin2.user,
+      admin2.ata,
+      getSettingsPDA(program.programId),
+      admin2.user,
+      100 * LAMPORTS_PER_SOL
+    );
+    const carriedTx = await program.methods
+      .pullRewardStream()
+      .accounts({ treasuryTokenAccount: admin2.ata })
+      .rpc();
+    const carriedTxinfo = await waitForTransaction(
+      provider.connection,
+      carriedTx
+    );
+    const carriedEvents = parseCpiEvents(program, carriedTxinfo);
+    expect(carriedEvents[0].data.amount.toNumber()).to.eq(
+      (carriedTxinfo.blockTime - limited.lastPullTime.toNumber()) * RATE
+    );
+
+    // Stops the stream for the rest of the tests
+    await program.methods
+      .configureRewardStream(new anchor.BN(0), new anchor.BN(0))
+      .accounts({
+        administrator: admin2.user.publicKey,
+        treasuryTokenAccount: admin2.ata,
+      })
+      .signers([admin2.user])
+      .rpc();
+  });
+
+  it("Admin2 ends the campaign now", async () => {
+    const slot = await provider.connection.getSlot();
+    const now = await provider.connection.getBlockTime(slot);
+    const tx = await program.methods
+      .configureRewardsEndTime(new anchor.BN(now))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("rewardsEndTimeConfigured");
+    expect(events[0].data.oldRewardsEndTime).to.be.null;
+    expect(events[0].data.newRewardsEndTime.toNumber()).to.eq(now);
+    // Accrual up to the change is settled under the old end time
+    const stats = await program.account.stats.fetch(
+      getStatsPDA(program.programId)
+    );
+    expect(events[0].data.effectiveTime.toString()).to.eq(
+      stats.lastUpdateTime.toString()
+    );
+    expect(events[0].data.rewardPerTokenStoredNumerator.toString()).to.eq(
+      stats.rewardPerTokenStoredNumerator.toString()
+    );
+    expect(events[0].data.totalRewardPromised.toString()).to.eq(
+      stats.totalRewardPromised.toString()
+    );
+
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settings = await program.account.settings.fetch(settingsPDA);
+    expect(settings.rewardsEndTime.toNumber()).to.equal(now);
+  });
+
+  it("   User1 cannot configure rewards end time", async () => {
+    try {
+      await program.methods
+        .configureRewardsEndTime(null)
+        .accounts({
+          administrator: user1.user.publicKey,
+        })
+        .signers([user1.user])
+        .rpc();
+      expect.fail("User1 should not be able to configure rewards end time");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("ConstraintHasOne");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("Admin2 sets reward rate to 8% after the campaign ended", async () => {
+    await program.methods
+      .configureRewardRatio(new anchor.BN(REWARD_RATE_1))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+  });
+
+  it("   Rewards do not accrue after the campaign end time", async () => {
+    const user2RewardsPrior = await program.methods
+      .viewCurrentRewards(user2.user.publicKey)
+      .view();
+    const unallocatedRewardsPrior = await program.methods
+      .viewUnallocatedRewards()
+      .view();
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+
+    const user2RewardsAfter = await program.methods
+      .viewCurrentRewards(user2.user.publicKey)
+      .view();
+    const unallocatedRewardsAfter = await program.methods
+      .viewUnallocatedRewards()
+      .view();
+
+    expect(user2RewardsAfter.toNumber()).to.equal(user2RewardsPrior.toNumber());
+    expect(unallocatedRewardsAfter.toNumber()).to.equal(
+      unallocatedRewardsPrior.toNumber()
+    );
+    expect(
+      (await program.methods.viewRewardRunway().view()).toString()
+    ).to.equal("18446744073709551615");
+  });
+
+  it("Admin2 sets reward rate back to 0 and removes the campaign end time", async () => {
+    await program.methods
+      .configureRewardRatio(new anchor.BN(REWARD_RATE_0))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    await program.methods
+      .configureRewardsEndTime(null)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settings = await program.account.settings.fetch(settingsPDA);
+    expect(settings.rewardsEndTime).to.be.null;
+  });
+
+  it("Admin2 enables solvency mode", async () => {
+    const tx = await program.methods
+      .configureSolvencyMode(true)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("solvencyModeConfigured");
+    expect(events[0].data.administrator).to.deep.eq(admin2.user.publicKey);
+    expect(events[0].data.oldEnabled).to.be.false;
+    expect(events[0].data.newEnabled).to.be.true;
+    expect(events[0].data.effectiveTime.toString()).to.eq(
+      events[0].data.metadata.timestamp.toString()
+    );
+
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settings = await program.account.settings.fetch(settingsPDA);
+    expect(settings.solvencyModeEnabled).to.be.true;
+  });
+
+  it("   User1 cannot configure solvency mode", async () => {
+    try {
+      await program.methods
+        .configureSolvencyMode(false)
+        .accounts({
+          administrator: user1.user.publicKey,
+        })
+        .signers([user1.user])
+        .rpc();
+      expect.fail("User1 should not be able to configure solvency mode");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("ConstraintHasOne");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   ViewUnallocatedRewards is not negative in solvency mode", async () => {
+    const unallocatedRewards = await program.methods
+      .viewUnallocatedRewards()
+      .view();
+    expect(unallocatedRewards.toNumber()).to.be.gte(0);
+  });
+
+  it("   Accrual halts once promised rewards reach provided rewards, and resumes with new rewards", async () => {
+    const statsPDA = getStatsPDA(program.programId);
+    // Settles the accumulators, without changing the mode
+    const settle = () =>
+      program.methods
+        .configureSolvencyMode(true)
+        .accounts({
+          administrator: admin2.user.publicKey,
+        })
+        .signers([admin2.user])
+        .rpc();
+
+    // The highest possible rate drains the pool within seconds
+    await program.methods
+      .configureRewardRatio(new anchor.BN(REWARD_RATE_MAX))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    for (let attempt = 0; attempt < 30; attempt++) {
+      const unallocatedRewards = await program.methods
+        .viewUnallocatedRewards()
+        .view();
+      if (unallocatedRewards.isZero()) {
+        break;
+      }
+      await new Promise((resolve) => setTimeout(resolve, 1000));
+    }
+
+    await settle();
+    const halted = await program.account.stats.fetch(statsPDA);
+    expect(halted.totalRewardPromised.toString()).to.eq(
+      halted.totalRewardProvided.add(halted.totalRewardForfeited).toString()
+    );
+
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await settle();
+    const stillHalted = await program.account.stats.fetch(statsPDA);
+    expect(stillHalted.rewardPerTokenStoredNumerator.toString()).to.eq(
+      halted.rewardPerTokenStoredNumerator.toString()
+    );
+    expect(stillHalted.totalRewardPromised.toString()).to.eq(
+      halted.totalRewardPromised.toString()
+    );
+
+    await program.methods
+      .addRewards(new anchor.BN(LAMPORTS_PER_SOL))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    await new Promise((resolve) => setTimeout(resolve, 2000));
+    await settle();
+    const resumed = await program.account.stats.fetch(statsPDA);
+    expect(
+      resumed.rewardPerTokenStoredNumerator.gt(
+        stillHalted.rewardPerTokenStoredNumerator
+      )
+    ).to.be.true;
+    // The added rewards are promised, but not more
+    expect(resumed.totalRewardPromised.toString()).to.eq(
+      resumed.totalRewardProvided.add(resumed.totalRewardForfeited).toString()
+    );
+
+    await program.methods
+      .configureRewardRatio(new anchor.BN(REWARD_RATE_0))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+  });
+
+  it("Admin2 disables solvency mode", async () => {
+    await program.methods
+      .configureSolvencyMode(false)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settings = await program.account.settings.fetch(settingsPDA);
+    expect(settings.solvencyModeEnabled).to.be.false;
+  });
+
+  it("Admin2 enables partial reward payout", async () => {
+    const tx = await program.methods
+      .configurePartialRewardPayout(true)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("partialRewardPayoutConfigured");
+    expect(events[0].data.enabled).to.be.true;
+
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settings = await program.account.settings.fetch(settingsPDA);
+    expect(settings.partialRewardPayoutEnabled).to.be.true;
+  });
+
+  it("   User1 cannot claim reward debt, as there is none", async () => {
+    try {
+      await program.methods
+        .claimRewardDebt()
+        .accounts({
+          user: user1.user.publicKey,
+        })
+        .signers([user1.user])
+        .rpc();
+      expect.fail("User1 should not be able to claim reward debt");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("NoRewardDebt");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("- User3 withdraws in full, as the pool is solvent [total: 0 | staked: 0]", async () => {
+    const tx = await program.methods
+      .withdraw()
+      .accounts({
+        user: user3.user.publicKey,
+      })
+      .signers([user3.user])
+      .rpc();
+
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("withdrawn");
+    expect(events[0].data.tokenAmount.toNumber()).to.eq(
+      USER3_STAKE_AMOUNT * 2 * LAMPORTS_PER_SOL
+    );
+    expect(events[0].data.rewardAmount.toNumber()).to.be.gt(0);
+    expect(events[0].data.unpaidRewardAmount.toNumber()).to.eq(0);
+
+    const userInfoPDA = getUserInfoPDA(program.programId, user3.user.publicKey);
+    const userInfo = await program.account.userInfo.fetch(userInfoPDA);
+    expect(userInfo.rewardDebtAmount.toNumber()).to.equal(0);
+
+    const statsPDA = getStatsPDA(program.programId);
+    const stats = await program.account.stats.fetch(statsPDA);
+    expect(stats.totalRewardDebt.toNumber()).to.equal(0);
+  });
+
+  it("Admin2 disables partial reward payout", async () => {
+    await program.methods
+      .configurePartialRewardPayout(false)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    const settingsPDA = getSettingsPDA(program.programId);
+    const settings = await program.account.settings.fetch(settingsPDA);
+    expect(settings.partialRewardPayoutEnabled).to.be.false;
+  });
+
+  it("   ViewPendingWithdrawals is 0, as the exit queue is empty", async () => {
+    let rsp = await program.methods.viewPendingWithdrawals().simulate();
+
+    expect(rsp.events[0].name).to.equal("pendingWithdrawalsViewed");
+    expect(rsp.events[0].data.totalWithdrawalRequestAmount.toNumber()).to.equal(
+      0
+    );
+    expect(
+      rsp.events[0].data.totalWithdrawalRequestRewardAmount.toNumber()
+    ).to.equal(0);
+    expect(rsp.events[0].data.totalRewardDebt.toNumber()).to.equal(0);
+    expect(
+      rsp.events[0].data.protocolTokenAccountAmount.toNumber()
+    ).to.be.greaterThan(0);
+    expect(
+      (await program.methods.viewPendingWithdrawals().view()).toNumber()
+    ).to.equal(0);
+  });
+
+  it("   Stats track lifetime counters", async () => {
+    const statsPDA = getStatsPDA(program.programId);
+    const stats = await program.account.stats.fetch(statsPDA);
+    // Only User2 is still staking
+    expect(stats.activeStakers.toNumber()).to.equal(1);
+    // User1, User4 once, User2 and User3 twice (staked again after requesting withdrawal)
+    expect(stats.totalPositionsOpened.toNumber()).to.equal(6);
+    expect(stats.lifetimeStaked.toNumber()).to.equal(
+      (USER1_STAKE_AMOUNT * 2 +
+        USER2_STAKE_AMOUNT * 2 +
+        USER3_STAKE_AMOUNT * 2 +
+        USER4_STAKE_AMOUNT) *
+        LAMPORTS_PER_SOL
+    );
+    expect(stats.lifetimeWithdrawn.toNumber()).to.equal(
+      (USER1_STAKE_AMOUNT * 2 +
+        USER2_STAKE_AMOUNT +
+        USER3_STAKE_AMOUNT * 2 +
+        USER4_STAKE_AMOUNT) *
+        LAMPORTS_PER_SOL
+    );
+    expect(stats.lifetimeRewardsPaid.toNumber()).to.be.greaterThan(0);
+
+    const user4InfoPDA = getUserInfoPDA(
+      program.programId,
+      user4.user.publicKey
+    );
+    const user4Info = await program.account.userInfo.fetch(user4InfoPDA);
+    expect(user4Info.lifetimeStaked.toNumber()).to.equal(
+      USER4_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(user4Info.lifetimeWithdrawn.toNumber()).to.equal(
+      USER4_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(user4Info.lifetimeRewardsPaid.toNumber()).to.equal(0);
+    expect(user4Info.lifetimeRewardsForfeited.toNumber()).to.equal(
+      stats.totalRewardForfeited.toNumber()
+    );
+  });
+
+  it("   Admin2 cannot migrate Stats, as it is already in the current layout", async () => {
+    try {
+      await program.methods
+        .migrateStats({
+          totalRewardForfeited: new anchor.BN(0),
+          totalWithdrawalRequestAmount: new anchor.BN(0),
+          totalWithdrawalRequestRewardAmount: new anchor.BN(0),
+          activeStakers: new anchor.BN(0),
+          totalPositionsOpened: new anchor.BN(0),
+          lifetimeStaked: new anchor.BN(0),
+          lifetimeWithdrawn: new anchor.BN(0),
+          lifetimeRewardsPaid: new anchor.BN(0),
+        })
+        .accounts({
+          administrator: admin2.user.publicKey,
+        })
+        .signers([admin2.user])
+        .rpc();
+      expect.fail("Stats should not be migrated twice");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("AccountAlreadyMigrated");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Admin2 cannot migrate Settings, as it is already in the current layout", async () => {
+    try {
+      await program.methods
+        .migrateSettings()
+        .accounts({
+          administrator: admin2.user.publicKey,
+        })
+        .signers([admin2.user])
+        .rpc();
+      expect.fail("Settings should not be migrated twice");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("AccountAlreadyMigrated");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   User1 cannot migrate User2's account, as it is already in the current layout", async () => {
+    try {
+      await program.methods
+        .migrateUserInfo()
+        .accounts({
+          payer: user1.user.publicKey,
+          user: user2.user.publicKey,
+        })
+        .signers([user1.user])
+        .rpc();
+      expect.fail("User info should not be migrated twice");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("AccountAlreadyMigrated");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Anyone can view User2's position", async () => {
+    const position = await program.methods
+      .viewPosition(user2.user.publicKey)
+      .view();
+
+    expect(position.owner).to.deep.eq(user2.user.publicKey);
+    expect(position.stakeAmount.toNumber()).to.eq(
+      USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(position.stakedAt.toNumber()).to.be.greaterThan(0);
+    expect(position.withdrawalRequestAmount.toNumber()).to.eq(0);
+    expect(position.withdrawalRequestRewardAmount.toNumber()).to.eq(0);
+    expect(position.withdrawalUnlockTime).to.be.null;
+    expect(position.canWithdraw).to.be.false;
+    // Reward rate was set to 0
+    expect(
+      position.effectiveRewardRateYearlyPercentageNumerator.toNumber()
+    ).to.eq(0);
+  });
+
+  it("   View pool overview", async () => {
+    const pool = await program.methods.viewPool().view();
+
+    expect(pool.rewardRateYearlyPercentageNumerator.toNumber()).to.eq(0);
+    expect(pool.rewardRatePerSecondPerTokenNumerator.toNumber()).to.eq(0);
+    expect(pool.totalStaked.toNumber()).to.eq(
+      USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(pool.runwaySeconds.toString()).to.eq("18446744073709551615");
+    expect(pool.withdrawalDelaySeconds).to.eq(0);
+    expect(pool.rewardsEndTime).to.be.null;
+    expect(pool.accrualPaused).to.be.false;
+    expect(pool.solvencyModeEnabled).to.be.false;
+    expect(pool.partialRewardPayoutEnabled).to.be.false;
+
+    const stats = await program.account.stats.fetch(
+      getStatsPDA(program.programId)
+    );
+    expect(pool.totalRewardProvided.toNumber()).to.eq(
+      stats.totalRewardProvided.toNumber()
+    );
+  });
+
+  it("   User2's projected rewards do not grow, as the reward rate is 0", async () => {
+    const slot = await provider.connection.getSlot();
+    const now = await provider.connection.getBlockTime(slot);
+    const in30Days = now + 30 * 24 * 60 * 60;
+
+    const position = await program.methods
+      .viewPosition(user2.user.publicKey)
+      .view();
+    const projection = await program.methods
+      .viewProjectedRewards(user2.user.publicKey, new anchor.BN(in30Days))
+      .view();
+
+    expect(projection.timestamp.toNumber()).to.eq(in30Days);
+    expect(projection.withdrawalRequestRewardAmount.toString()).to.eq(
+      position.withdrawalRequestRewardAmount.toString()
+    );
+    expect(projection.totalReward.toNumber()).to.eq(
+      position.capturedReward
+        .add(position.uncapturedReward)
+        .add(position.withdrawalRequestRewardAmount)
+        .toNumber()
+    );
+    const unallocatedRewards = await program.methods
+      .viewUnallocatedRewards()
+      .view();
+    expect(projection.unallocatedRewards.toString()).to.eq(
+      unallocatedRewards.toString()
+    );
+  });
+
+  it("   Rewards can not be projected into the past", async () => {
+    try {
+      await program.methods
+        .viewProjectedRewards(user2.user.publicKey, new anchor.BN(1))
+        .view();
+      expect.fail("Projection into the past should fail");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("InvalidTimestamp");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Protocol token account covers the reward liability", async () => {
+    const solvency = await program.methods.viewSolvency().view();
+
+    const protocolTokenAccount = await getAccount(
+      provider.connection,
+      anchor.utils.token.associatedAddress({
+        mint: tokenMint,
+        owner: getSettingsPDA(program.programId),
+      })
+    );
+    expect(solvency.protocolTokenAccountAmount.toString()).to.eq(
+      protocolTokenAccount.amount.toString()
+    );
+    expect(solvency.totalStaked.toNumber()).to.eq(
+      USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(solvency.totalWithdrawalRequestAmount.toNumber()).to.eq(0);
+    expect(solvency.userVaultAmount.toNumber()).to.eq(
+      USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(solvency.totalWithdrawalRequestRewardAmount.toNumber()).to.eq(0);
+    expect(solvency.totalRewardDebt.toNumber()).to.eq(0);
+    expect(solvency.rewardSurplus.toString()).to.eq(
+      solvency.protocolTokenAccountAmount
+        .sub(solvency.rewardLiability)
+        .toString()
+    );
+    // Covered, so the surplus is the rewards provided, but not promised yet
+    const stats = await program.account.stats.fetch(
+      getStatsPDA(program.programId)
+    );
+    expect(solvency.rewardSurplus.toNumber()).to.be.at.most(
+      stats.totalRewardProvided
+        .sub(stats.totalRewardPromised)
+        .add(stats.totalRewardForfeited)
+        .toNumber()
+    );
+  });
+
+  it("   Positions of many users are viewed against the same accumulator", async () => {
+    const users = [user1, user2, user3];
+    const positions = await program.methods
+      .viewPositions()
+      .remainingAccounts(
+        users.map((user) => ({
+          pubkey: getUserInfoPDA(program.programId, user.user.publicKey),
+          isWritable: false,
+          isSigner: false,
+        }))
+      )
+      .view();
+
+    expect(positions.positions.length).to.eq(users.length);
+    for (const [index, user] of users.entries()) {
+      const position = positions.positions[index];
+      const userInfo = await program.account.userInfo.fetch(
+        getUserInfoPDA(program.programId, user.user.publicKey)
+      );
+      expect(position.owner).to.deep.eq(user.user.publicKey);
+      expect(position.stakeAmount.toString()).to.eq(
+        userInfo.stakeAmount.toString()
+      );
+      expect(position.totalReward.toString()).to.eq(
+        position.capturedReward
+          .add(position.uncapturedReward)
+          .add(position.withdrawalRequestRewardAmount)
+          .toString()
+      );
+    }
+    expect(positions.rewardPerTokenStoredNumerator.gt(new anchor.BN(0))).to.be
+      .true;
+  });
+
+  it("   Positions can not be viewed with other accounts", async () => {
+    try {
+      await program.methods
+        .viewPositions()
+        .remainingAccounts([
+          {
+            pubkey: getSettingsPDA(program.programId),
+            isWritable: false,
+            isSigner: false,
+          },
+        ])
+        .view();
+      expect.fail("Viewing a non user info account should fail");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("AccountDiscriminatorMismatch");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Events carry a gapless sequence number and the post-state", async () => {
+    const statsPDA = getStatsPDA(program.programId);
+    const statsBefore = await program.account.stats.fetch(statsPDA);
+
+    for (const offset of [1, 2]) {
+      const tx = await program.methods
+        .configurePartialRewardPayout(false)
+        .accounts({
+          administrator: admin2.user.publicKey,
+        })
+        .signers([admin2.user])
+        .rpc();
+      const txinfo = await waitForTransaction(provider.connection, tx);
+      const events = parseCpiEvents(program, txinfo);
+      expect(events.length).to.eq(1);
+
+      const stats = await program.account.stats.fetch(statsPDA);
+      const metadata = events[0].data.metadata;
+      expect(metadata.sequence.toNumber()).to.eq(
+        statsBefore.eventSequence.toNumber() + offset
+      );
+      expect(metadata.sequence.toNumber()).to.eq(
+        stats.eventSequence.toNumber()
+      );
+      expect(metadata.slot.toNumber()).to.eq(txinfo.slot);
+      expect(metadata.timestamp.toNumber()).to.eq(txinfo.blockTime);
+      expect(metadata.totalStaked.toNumber()).to.eq(
+        stats.totalStaked.toNumber()
+      );
+      expect(metadata.rewardPerTokenStoredNumerator.toString()).to.eq(
+        stats.rewardPerTokenStoredNumerator.toString()
+      );
+    }
+  });
+
+  it("   Rate history records every reward rate change", async () => {
+    const rateHistory = await program.account.rateHistory.fetch(
+      getRateHistoryPDA(program.programId)
+    );
+    const rates = rateHistory.checkpoints.map((checkpoint) =>
+      checkpoint.newRewardRatePerSecondPerTokenNumerator.toString()
+    );
+    const ratePerSecond = (yearly: number | string) =>
+      new anchor.BN(yearly)
+        .mul(new anchor.BN(10).pow(new anchor.BN(15)))
+        .div(new anchor.BN(365 * 24 * 60 * 60))
+        .toString();
+    expect(rates).to.deep.eq([
+      ratePerSecond(REWARD_RATE_0), // Initial rate
+      ratePerSecond(REWARD_RATE_1),
+      ratePerSecond(REWARD_RATE_0),
+      ratePerSecond(REWARD_RATE_1),
+      ratePerSecond(REWARD_RATE_0),
+      ratePerSecond(REWARD_RATE_MAX),
+      ratePerSecond(REWARD_RATE_0),
+    ]);
+    for (const [index, checkpoint] of rateHistory.checkpoints.entries()) {
+      if (index > 0) {
+        const previous = rateHistory.checkpoints[index - 1];
+        expect(
+          checkpoint.oldRewardRatePerSecondPerTokenNumerator.toString()
+        ).to.eq(previous.newRewardRatePerSecondPerTokenNumerator.toString());
+        expect(checkpoint.timestamp.toNumber()).to.be.gte(
+          previous.timestamp.toNumber()
+        );
+      }
+    }
+  });
+
+  it("   Reward rate can be viewed at any past moment", async () => {
+    const rateHistory = await program.account.rateHistory.fetch(
+      getRateHistoryPDA(program.programId)
+    );
+    const initial = rateHistory.checkpoints[0];
+    const first = rateHistory.checkpoints[1];
+
+    const before = await program.methods
+      .viewRewardRateAt(initial.timestamp.subn(1))
+      .view();
+    expect(before.rewardRatePerSecondPerTokenNumerator.toString()).to.eq(
+      initial.oldRewardRatePerSecondPerTokenNumerator.toString()
+    );
+    expect(before.effectiveSince).to.be.null;
+
+    // The initial rate applies from the initialization up to the first change
+    const beforeFirst = await program.methods
+      .viewRewardRateAt(first.timestamp.subn(1))
+      .view();
+    expect(beforeFirst.rewardRateYearlyPercentageNumerator.toNumber()).to.eq(
+      REWARD_RATE_0
+    );
+    expect(beforeFirst.effectiveSince.toString()).to.eq(
+      initial.timestamp.toString()
+    );
+
+    const at = await program.methods.viewRewardRateAt(first.timestamp).view();
+    expect(at.rewardRatePerSecondPerTokenNumerator.toString()).to.eq(
+      first.newRewardRatePerSecondPerTokenNumerator.toString()
+    );
+    expect(at.rewardRateYearlyPercentageNumerator.toNumber()).to.eq(
+      REWARD_RATE_1
+    );
+    expect(at.effectiveSince.toString()).to.eq(first.timestamp.toString());
+  });
+
+  it("   Staked tokens are mirrored into a governance voter weight record", async () => {
+    const realm = Keypair.generate().publicKey;
+    const tx = await program.methods
+      .updateVoterWeightRecord(realm)
+      .accounts({
+        payer: user1.user.publicKey,
+        user: user2.user.publicKey,
+      })
+      .signers([user1.user])
+      .rpc();
+
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events.length).to.eq(1);
+    expect(events[0].name).to.eq("voterWeightRecordUpdated");
+    expect(events[0].data.voterWeight.toNumber()).to.eq(
+      USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+
+    const [voterWeightRecordPDA] = PublicKey.findProgramAddressSync(
+      [
+        Buffer.from("voter_weight_record"),
+        realm.toBuffer(),
+        user2.user.publicKey.toBuffer(),
+      ],
+      program.programId
+    );
+    const voterWeightRecord = await program.account.voterWeightRecord.fetch(
+      voterWeightRecordPDA
+    );
+    expect(voterWeightRecord.realm).to.deep.eq(realm);
+    expect(voterWeightRecord.governingTokenMint).to.deep.eq(tokenMint);
+    expect(voterWeightRecord.governingTokenOwner).to.deep.eq(
+      user2.user.publicKey
+    );
+    expect(voterWeightRecord.voterWeight.toNumber()).to.eq(
+      USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+    expect(voterWeightRecord.voterWeightExpiry.toNumber()).to.eq(txinfo.slot);
+    expect(voterWeightRecord.weightAction).to.be.null;
+  });
+
+  it("   Voter weight includes the age bonus", async () => {
+    // +100% voting power without a ramp up
+    await program.methods
+      .configureVoterWeightAgeBonus(
+        new anchor.BN(1_000_000_000_000),
+        new anchor.BN(0)
+      )
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    const realm = Keypair.generate().publicKey;
+    const tx = await program.methods
+      .updateVoterWeightRecord(realm)
+      .accounts({
+        payer: user2.user.publicKey,
+        user: user2.user.publicKey,
+      })
+      .signers([user2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events[0].data.voterWeight.toNumber()).to.eq(
+      2 * USER2_STAKE_AMOUNT * LAMPORTS_PER_SOL
+    );
+
+    await program.methods
+      .configureVoterWeightAgeBonus(new anchor.BN(0), new anchor.BN(0))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+  });
+
+  it("   Stake history records the balance after every change", async () => {
+    const checkpoints = await fetchStakeCheckpoints(
+      provider.connection,
+      getStakeHistoryPDA(program.programId, user2.user.publicKey)
+    );
+    const userInfo = await program.account.userInfo.fetch(
+      getUserInfoPDA(program.programId, user2.user.publicKey)
+    );
+    expect(checkpoints.length).to.be.gt(0);
+    expect(checkpoints[checkpoints.length - 1].amount.toString()).to.eq(
+      userInfo.stakeAmount.toString()
+    );
+    for (const [index, checkpoint] of checkpoints.entries()) {
+      if (index > 0) {
+        const previous = checkpoints[index - 1];
+        expect(checkpoint.slot.toNumber()).to.be.gt(previous.slot.toNumber());
+        expect(checkpoint.timestamp.toNumber()).to.be.gte(
+          previous.timestamp.toNumber()
+        );
+      }
+    }
+
+    const totalCheckpoints = await fetchStakeCheckpoints(
+      provider.connection,
+      getTotalStakeHistoryPDA(program.programId)
+    );
+    const stats = await program.account.stats.fetch(
+      getStatsPDA(program.programId)
+    );
+    expect(
+      totalCheckpoints[totalCheckpoints.length - 1].amount.toString()
+    ).to.eq(stats.totalStaked.toString());
+  });
+
+  it("   Staked balances can be viewed at any past moment", async () => {
+    const checkpoints = await fetchStakeCheckpoints(
+      provider.connection,
+      getStakeHistoryPDA(program.programId, user2.user.publicKey)
+    );
+    const first = checkpoints[0];
+    const last = checkpoints[checkpoints.length - 1];
+
+    const before = await program.methods
+      .viewStakedAtTime(user2.user.publicKey, first.timestamp.subn(1))
+      .accounts({
+        stakeHistory: getStakeHistoryPDA(
+          program.programId,
+          user2.user.publicKey
+        ),
+      })
+      .view();
+    expect(before.toNumber()).to.eq(0);
+
+    const latest = await program.methods
+      .viewStakedAtTime(user2.user.publicKey, last.timestamp)
+      .accounts({
+        stakeHistory: getStakeHistoryPDA(
+          program.programId,
+          user2.user.publicKey
+        ),
+      })
+      .view();
+    expect(latest.toString()).to.eq(last.amount.toString());
+
+    const totalCheckpoints = await fetchStakeCheckpoints(
+      provider.connection,
+      getTotalStakeHistoryPDA(program.programId)
+    );
+    for (const checkpoint of totalCheckpoints) {
+      const total = await program.methods
+        .viewTotalStakedAtTime(checkpoint.timestamp)
+        .view();
+      // Later checkpoints of the same second win
+      const expected = totalCheckpoints
+        .filter((other) => other.timestamp.lte(checkpoint.timestamp))
+        .pop();
+      expect(total.toString()).to.eq(expected.amount.toString());
+    }
+  });
+
+  it("   Loyalty schedule is configured by the administrator", async () => {
+    const tx = await program.methods
+      .configureLoyaltySchedule([
+        {
+          minStakedDays: new anchor.BN(90),
+          bonusRateYearlyPercentageNumerator: new anchor.BN(10_000_000_000),
+        },
+        {
+          minStakedDays: new anchor.BN(180),
+          bonusRateYearlyPercentageNumerator: new anchor.BN(20_000_000_000),
+        },
+      ])
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events[0].name).to.eq("loyaltyScheduleConfigured");
+    expect(events[0].data.oldLoyaltyTiers).to.deep.eq([]);
+    // Memberships of the old schedule no longer count
+    expect(events[0].data.loyaltyScheduleEpoch.toNumber()).to.eq(2);
+
+    const settings = await program.account.settings.fetch(
+      getSettingsPDA(program.programId)
+    );
+    expect(
+      settings.loyaltyTiers.map((tier) => tier.minStakedSeconds)
+    ).to.deep.eq([90 * 24 * 60 * 60, 180 * 24 * 60 * 60]);
+    expect(
+      settings.loyaltyTiers[0].bonusRatePerSecondPerTokenNumerator.toString()
+    ).to.eq(
+      new anchor.BN(10_000_000_000)
+        .mul(new anchor.BN(10).pow(new anchor.BN(15)))
+        .div(new anchor.BN(365 * 24 * 60 * 60))
+        .toString()
+    );
+
+    // Young positions only earn the base rewards
+    const userInfoBefore = await program.account.userInfo.fetch(
+      getUserInfoPDA(program.programId, user2.user.publicKey)
+    );
+    await program.methods
+      .stake(new anchor.BN(1))
+      .accounts({
+        user: user2.user.publicKey,
+        tokenMint: tokenMint,
+      })
+      .signers([user2.user])
+      .rpc();
+    const stats = await program.account.stats.fetch(
+      getStatsPDA(program.programId)
+    );
+    const userInfoAfter = await program.account.userInfo.fetch(
+      getUserInfoPDA(program.programId, user2.user.publicKey)
+    );
+    const baseReward = userInfoBefore.stakeAmount
+      .mul(
+        stats.rewardPerTokenStoredNumerator.sub(
+          userInfoBefore.rewardPerTokenPaidNumerator
+        )
+      )
+      .div(new anchor.BN(10).pow(new anchor.BN(27)));
+    expect(
+      userInfoAfter.capturedReward.sub(userInfoBefore.capturedReward).toString()
+    ).to.eq(baseReward.toString());
+    expect(userInfoAfter.loyaltyTier).to.be.null;
+    expect(userInfoAfter.loyaltyScheduleEpoch.toString()).to.eq(
+      stats.loyaltyScheduleEpoch.toString()
+    );
+    expect(stats.loyaltyTierStaked.every((staked) => staked.isZero())).to.be
+      .true;
+  });
+
+  it("   Loyalty tiers must be ordered by a growing stake age", async () => {
+    try {
+      await program.methods
+        .configureLoyaltySchedule([
+          {
+            minStakedDays: new anchor.BN(180),
+            bonusRateYearlyPercentageNumerator: new anchor.BN(20_000_000_000),
+          },
+          {
+            minStakedDays: new anchor.BN(90),
+            bonusRateYearlyPercentageNumerator: new anchor.BN(10_000_000_000),
+          },
+        ])
+        .accounts({
+          administrator: admin2.user.publicKey,
+        })
+        .signers([admin2.user])
+        .rpc();
+      expect.fail("Unordered loyalty tiers should be rejected");
+    } catch (err) {
+      if ("error" in err && "errorCode" in err.error) {
+        expect(err.error.errorCode.code).to.eq("InvalidLoyaltySchedule");
+        return;
+      } else {
+        throw err;
+      }
+    }
+  });
+
+  it("   Anyone can update the loyalty tier of User2", async () => {
+    const tx = await program.methods
+      .updateLoyaltyTier()
+      .accounts({
+        user: user2.user.publicKey,
+      })
+      .rpc();
+
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events[0].name).to.eq("loyaltyTierUpdated");
+    expect(events[0].data.user).to.deep.eq(user2.user.publicKey);
+    // The position is younger than the first tier
+    expect(events[0].data.oldLoyaltyTier).to.be.null;
+    expect(events[0].data.newLoyaltyTier).to.be.null;
+
+    const position = await program.methods
+      .viewPosition(user2.user.publicKey)
+      .view();
+    expect(position.loyaltyTier).to.be.null;
+    expect(position.reachedLoyaltyTier).to.be.null;
+  });
+
+  it("Admin2 enables partial reward payout, and the pool runs short", async () => {
+    await program.methods
+      .configurePartialRewardPayout(true)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    // Without solvency mode, accrual goes on after the provided rewards are promised
+    await program.methods
+      .configureRewardRatio(new anchor.BN(REWARD_RATE_1))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+    await program.methods
+      .configureRewardRatio(new anchor.BN(REWARD_RATE_0))
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+
+    const unallocatedRewards = await program.methods
+      .viewUnallocatedRewards()
+      .view();
+    expect(unallocatedRewards.isNeg()).to.be.true;
+  });
+
+  it("- User2 withdraws a partial reward, the rest becomes a reward debt", async () => {
+    const protocolTokenAccountAddress = anchor.utils.token.associatedAddress({
+      mint: tokenMint,
+      owner: getSettingsPDA(program.programId),
+    });
+    const statsPDA = getStatsPDA(program.programId);
+    const userInfoPDA = getUserInfoPDA(program.programId, user2.user.publicKey);
+
+    await program.methods
+      .requestWithdrawal()
+      .accounts({
+        user: user2.user.publicKey,
+      })
+      .signers([user2.user])
+      .rpc();
+    const userInfoBefore = await program.account.userInfo.fetch(userInfoPDA);
+    const statsBefore = await program.account.stats.fetch(statsPDA);
+    const protocolTokenAccountBefore = await getAccount(
+      provider.connection,
+      protocolTokenAccountAddress
+    );
+    const available = new anchor.BN(
+      protocolTokenAccountBefore.amount.toString()
+    );
+    expect(userInfoBefore.withdrawalRequestRewardAmount.gt(available)).to.be
+      .true;
+
+    const tx = await program.methods
+      .withdraw()
+      .accounts({
+        user: user2.user.publicKey,
+      })
+      .signers([user2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events[0].name).to.eq("withdrawn");
+    const unpaid = userInfoBefore.withdrawalRequestRewardAmount.sub(available);
+    expect(events[0].data.rewardAmount.toString()).to.eq(available.toString());
+    expect(events[0].data.unpaidRewardAmount.toString()).to.eq(
+      unpaid.toString()
+    );
+
+    const userInfo = await program.account.userInfo.fetch(userInfoPDA);
+    expect(userInfo.rewardDebtAmount.toString()).to.eq(unpaid.toString());
+    const stats = await program.account.stats.fetch(statsPDA);
+    expect(
+      stats.totalRewardDebt.sub(statsBefore.totalRewardDebt).toString()
+    ).to.eq(unpaid.toString());
+    // The reward debt is owed from the protocol token account
+    const owed = await program.methods.viewPendingWithdrawals().view();
+    expect(owed.toString()).to.eq(
+      stats.totalWithdrawalRequestRewardAmount
+        .add(stats.totalRewardDebt)
+        .toString()
+    );
+    const protocolTokenAccount = await getAccount(
+      provider.connection,
+      protocolTokenAccountAddress
+    );
+    expect(protocolTokenAccount.amount.toString()).to.eq("0");
+  });
+
+  it("   User2 claims as much of the reward debt as the pool covers", async () => {
+    const userInfoPDA = getUserInfoPDA(program.programId, user2.user.publicKey);
+    const debt = (await program.account.userInfo.fetch(userInfoPDA))
+      .rewardDebtAmount;
+    const partial = debt.divn(2);
+
+    await program.methods
+      .addRewards(partial)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    const tx = await program.methods
+      .claimRewardDebt()
+      .accounts({
+        user: user2.user.publicKey,
+      })
+      .signers([user2.user])
+      .rpc();
+    const txinfo = await waitForTransaction(provider.connection, tx);
+    const events = parseCpiEvents(program, txinfo);
+    expect(events[0].name).to.eq("rewardDebtClaimed");
+    expect(events[0].data.amount.toString()).to.eq(partial.toString());
+    expect(events[0].data.remainingRewardDebtAmount.toString()).to.eq(
+      debt.sub(partial).toString()
+    );
+
+    const userInfo = await program.account.userInfo.fetch(userInfoPDA);
+    expect(userInfo.rewardDebtAmount.toString()).to.eq(
+      debt.sub(partial).toString()
+    );
+  });
+
+  it("   User2 claims the rest of the reward debt, once rewards are added", async () => {
+    const userInfoPDA = getUserInfoPDA(program.programId, user2.user.publicKey);
+    const statsPDA = getStatsPDA(program.programId);
+    const debt = (await program.account.userInfo.fetch(userInfoPDA))
+      .rewardDebtAmount;
+    const statsBefore = await program.account.stats.fetch(statsPDA);
+
+    await program.methods
+      .addRewards(debt)
+      .accounts({
+        administrator: admin2.user.publicKey,
+      })
+      .signers([admin2.user])
+      .rpc();
+    await program.methods
+      .claimRewardDebt()
+      .accounts({
+        user: user2.user.publicKey,
+      })
+      .signers([user2.user])
+      .rpc();
+
+    const userInfo = await program.account.userInfo.fetch(userInfoPDA);
+    expect(userInfo.rewardDebtAmount.toNumber()).to.eq(0);
+    const stats = await program.account.stats.fetch(statsPDA);
+    expect(
+      statsBefore.totalRewardDebt.sub(stats.totalRewardDebt).toString()
+    ).to.eq(debt.toString());
+  });
 });